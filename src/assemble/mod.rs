@@ -0,0 +1,181 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use crate::input;
+use std::{fs, path::Path};
+
+/// A binary to write at a given offset of the assembled image
+#[derive(Debug)]
+pub struct Placement {
+    name: String,
+    offset: usize,
+    data: Vec<u8>,
+}
+
+impl Placement {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.data.len()
+    }
+}
+
+/// Parse a layout to assemble: one `offset binary` line per binary, the
+/// offset being either in decimal or in hexadecimal
+pub fn parse(content: &str) -> Result<Vec<(usize, String)>> {
+    let mut layout = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || Error::InvalidLayout(n + 1, line.to_string());
+        let (offset, binary) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let offset = input::parse_size(offset).ok_or_else(invalid)?;
+        layout.push((offset, binary.trim().to_string()));
+    }
+    Ok(layout)
+}
+
+/// Load the binaries of a layout file. Their paths are relative to the layout
+/// file. The ranges of the binaries carrying their load address (Intel HEX,
+/// S-record, ELF) keep their position relative to the first one.
+pub fn load_layout<P: AsRef<Path>>(layout_path: P) -> Result<Vec<Placement>> {
+    let content = fs::read_to_string(&layout_path)?;
+    let base_dir = layout_path
+        .as_ref()
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+
+    let mut placements = Vec::new();
+    for (offset, binary) in parse(&content)? {
+        let ranges = input::load(base_dir.join(&binary))?;
+        let base = ranges.first().and_then(|r| r.address()).unwrap_or(0);
+        for range in ranges.iter() {
+            let shift = range.address().map_or(0, |a| (a - base) as usize);
+            placements.push(Placement {
+                name: range.name(&binary),
+                offset: offset + shift,
+                data: range.data().to_vec(),
+            });
+        }
+    }
+    placements.sort_by_key(|p| p.offset);
+    Ok(placements)
+}
+
+/// Build an image of `size` bytes filled with `fill`, holding each binary at
+/// its offset. Overlapping binaries are refused, unless `allow_overlap` is set
+/// and the overlapping bytes are identical.
+pub fn assemble(
+    placements: &[Placement],
+    size: usize,
+    fill: u8,
+    allow_overlap: bool,
+) -> Result<Vec<u8>> {
+    for (i, a) in placements.iter().enumerate() {
+        if a.end() > size {
+            return Err(Error::DoesNotFit(a.name.clone()));
+        }
+        for b in placements[i + 1..].iter() {
+            if a.offset < b.end() && b.offset < a.end() {
+                let start = std::cmp::max(a.offset, b.offset);
+                let end = std::cmp::min(a.end(), b.end());
+                let identical = a.data[start - a.offset..end - a.offset]
+                    == b.data[start - b.offset..end - b.offset];
+                if !allow_overlap || !identical {
+                    return Err(Error::Overlap(a.name.clone(), b.name.clone()));
+                }
+            }
+        }
+    }
+
+    let mut image = vec![fill; size];
+    for p in placements.iter() {
+        image[p.offset..p.end()].copy_from_slice(&p.data);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(name: &str, offset: usize, data: &[u8]) -> Placement {
+        Placement {
+            name: name.to_string(),
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parse_layout() {
+        let layout = parse("# offset binary\n0 boot.bin\n\n0x400  rootfs.img # root\n").unwrap();
+        assert_eq!(
+            layout,
+            [
+                (0, "boot.bin".to_string()),
+                (0x400, "rootfs.img".to_string())
+            ]
+        );
+        assert!(matches!(
+            parse("0 a.bin\nzz b.bin"),
+            Err(Error::InvalidLayout(2, _))
+        ));
+        assert!(matches!(parse("0x10"), Err(Error::InvalidLayout(1, _))));
+    }
+
+    #[test]
+    fn padding() {
+        let placements = [placement("a", 1, &[1, 2]), placement("b", 5, &[3])];
+        let image = assemble(&placements, 8, 0xff, false).unwrap();
+        assert_eq!(image, [0xff, 1, 2, 0xff, 0xff, 3, 0xff, 0xff]);
+        // a binary ending at the end of the image
+        let image = assemble(&[placement("a", 6, &[1, 2])], 8, 0, false).unwrap();
+        assert_eq!(image, [0, 0, 0, 0, 0, 0, 1, 2]);
+    }
+
+    #[test]
+    fn too_small() {
+        let placements = [placement("a", 0, &[1, 2]), placement("b", 7, &[3, 4])];
+        assert!(matches!(
+            assemble(&placements, 8, 0, false),
+            Err(Error::DoesNotFit(name)) if name == "b"
+        ));
+    }
+
+    #[test]
+    fn overlaps() {
+        let placements = [placement("a", 0, &[1, 2, 3]), placement("b", 2, &[4, 5])];
+        for allow_overlap in [false, true] {
+            assert!(matches!(
+                assemble(&placements, 8, 0, allow_overlap),
+                Err(Error::Overlap(a, b)) if a == "a" && b == "b"
+            ));
+        }
+
+        // identical overlapping bytes
+        let placements = [placement("a", 0, &[1, 2, 3]), placement("b", 2, &[3, 4])];
+        assert!(matches!(
+            assemble(&placements, 8, 0, false),
+            Err(Error::Overlap(_, _))
+        ));
+        let image = assemble(&placements, 8, 0, true).unwrap();
+        assert_eq!(image, [1, 2, 3, 4, 0, 0, 0, 0]);
+    }
+}
@@ -5,48 +5,324 @@
 //
 //
 
-use clap::{crate_authors, crate_description, crate_version, App, Arg};
+use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg, SubCommand};
+
+const EXPORT_HELP: &str =
+    "Generate the partition layout of the binaries found, as 'format[=file]'.\n\
+    Formats are 'mtdparts', 'dts', 'genimage' and 'flashrom'";
+
+// Options of the search, shared by the commands working on its result
+fn search_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("flash_image")
+            .short("i")
+            .long("image")
+            .required(true)
+            .takes_value(true)
+            .help("The flash image to search in"),
+        Arg::with_name("binaries_list")
+            .short("b")
+            .long("binaries")
+            .required_unless_one(&["expect", "manifest", "old", "against", "duplicates", "uboot_env"])
+            .takes_value(true)
+            .multiple(true)
+            .help("List of binaries to search for: files, directories (searched recursively) or glob patterns"),
+        Arg::with_name("manifest")
+            .short("m")
+            .long("manifest")
+            .takes_value(true)
+            .help("Manifest of artefacts to search by digest, one 'sha256 size name' line each"),
+        Arg::with_name("include")
+            .long("include")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only search the files of the directories and glob patterns whose name matches this pattern"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Do not search the files of the directories and glob patterns whose name matches this pattern"),
+        Arg::with_name("min_size")
+            .long("min_size")
+            .takes_value(true)
+            .default_value("0")
+            .help("Do not search the files of the directories and glob patterns smaller than this size"),
+        Arg::with_name("bsize")
+            .short("s")
+            .long("size")
+            .takes_value(true)
+            .default_value("512")
+            .help("Page / block size"),
+        Arg::with_name("first_copy")
+            .long("first_copy")
+            .help("Only search the first copy of a flash image made of repeated copies (mirrored dump)"),
+        Arg::with_name("v_scale")
+            .long("v_scale")
+            .takes_value(true)
+            .help("Vertical scaling, default is half of the term size"),
+        Arg::with_name("h_scale")
+            .long("h_scale")
+            .takes_value(true)
+            .help("Horizontal scaling, default is half of the term size"),
+        Arg::with_name("dtb")
+            .long("dtb")
+            .takes_value(true)
+            .conflicts_with("dtb_scan")
+            .help("Device tree blob describing the flash partitions"),
+        Arg::with_name("dtb_scan")
+            .long("dtb_scan")
+            .help("Search the flash image for a device tree describing the flash partitions"),
+        Arg::with_name("flashrom_layout")
+            .short("l")
+            .long("layout")
+            .takes_value(true)
+            .conflicts_with_all(&["dtb", "dtb_scan"])
+            .help("flashrom layout file describing the flash regions"),
+        Arg::with_name("dtb_node")
+            .long("dtb_node")
+            .takes_value(true)
+            .help("Path of the 'fixed-partitions' node to use, default is the first one"),
+        Arg::with_name("erase_size")
+            .long("erase_size")
+            .takes_value(true)
+            .default_value("0x10000")
+            .help("Erase block size, the exported partitions are aligned on"),
+        Arg::with_name("load_base")
+            .long("load_base")
+            .takes_value(true)
+            .help("Address the flash image is mapped at, to check the load address of HEX, S-record and ELF binaries"),
+        Arg::with_name("uefi")
+            .long("uefi")
+            .help("List the UEFI firmware volumes and files, and search the binaries in them"),
+        Arg::with_name("squashfs")
+            .long("squashfs")
+            .help("Search the binaries among the files of the SquashFS filesystems"),
+        Arg::with_name("jffs2")
+            .long("jffs2")
+            .help("Search the binaries among the files rebuilt from the JFFS2 nodes"),
+        Arg::with_name("android")
+            .long("android")
+            .help("Report the Android boot images of the flash image, and their sections"),
+        Arg::with_name("uboot_env")
+            .long("uboot_env")
+            .help("Report the U-Boot environments of the flash image, and their variables"),
+        Arg::with_name("duplicates")
+            .long("duplicates")
+            .help("Report the runs of blocks appearing more than once in the flash image (A/B slots, redundant copies)"),
+        Arg::with_name("duplicate_min_size")
+            .long("duplicate_min_size")
+            .takes_value(true)
+            .default_value("0x1000")
+            .help("Do not report the duplicated runs of blocks smaller than this size"),
+    ]
+}
 
 pub fn build_cli() -> App<'static, 'static> {
     App::new("imgseek")
         .version(crate_version!())
         .about(crate_description!())
         .author(crate_authors!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&search_args())
         .arg(
-            Arg::with_name("flash_image")
-                .short("i")
-                .long("image")
-                .required(true)
+            Arg::with_name("expect")
+                .long("expect")
                 .takes_value(true)
-                .help("The flash image to search in"),
+                .multiple(true)
+                .value_name("region=binary")
+                .help("Check that a binary is the content of a region of the flash layout"),
         )
         .arg(
-            Arg::with_name("binaries_list")
-                .short("b")
-                .long("binaries")
-                .required(true)
+            Arg::with_name("export")
+                .long("export")
                 .takes_value(true)
                 .multiple(true)
-                .help("List of binaries to search for"),
+                .value_name("format[=file]")
+                .help(EXPORT_HELP),
         )
         .arg(
-            Arg::with_name("bsize")
-                .short("s")
-                .long("size")
+            Arg::with_name("mtd_id")
+                .long("mtd_id")
                 .takes_value(true)
-                .default_value("512")
-                .help("Page / block size"),
+                .default_value("flash")
+                .help("MTD device identifier of the exported 'mtdparts'"),
         )
-        .arg(
-            Arg::with_name("v_scale")
-                .long("v_scale")
-                .takes_value(true)
-                .help("Vertical scaling, default is half of the term size"),
+        .subcommand(
+            SubCommand::with_name("extract")
+                .about("Write each piece found, and each gap between them, to its own file")
+                .args(&search_args())
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Directory to write the files and their 'index.json' description to"),
+                ),
         )
-        .arg(
-            Arg::with_name("h_scale")
-                .long("h_scale")
-                .takes_value(true)
-                .help("Horizontal scaling, default is half of the term size"),
+        .subcommand(
+            SubCommand::with_name("assemble")
+                .about("Build a flash image from a layout of binaries")
+                .arg(
+                    Arg::with_name("assemble_layout")
+                        .short("l")
+                        .long("layout")
+                        .required(true)
+                        .takes_value(true)
+                        .help("Layout of the image, one 'offset binary' line each"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The flash image to write"),
+                )
+                .arg(
+                    Arg::with_name("image_size")
+                        .long("image_size")
+                        .takes_value(true)
+                        .help("Size of the flash image, default is the end of the last binary"),
+                )
+                .arg(
+                    Arg::with_name("fill")
+                        .long("fill")
+                        .takes_value(true)
+                        .default_value("0xff")
+                        .help("Byte written where there is no binary"),
+                )
+                .arg(Arg::with_name("allow_overlap").long("allow_overlap").help(
+                    "Allow the binaries to overlap, as long as the overlapping bytes are identical",
+                ))
+                .arg(
+                    Arg::with_name("v_scale")
+                        .long("v_scale")
+                        .takes_value(true)
+                        .help("Vertical scaling, default is half of the term size"),
+                )
+                .arg(
+                    Arg::with_name("h_scale")
+                        .long("h_scale")
+                        .takes_value(true)
+                        .help("Horizontal scaling, default is half of the term size"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("replace")
+                .about("Write a new binary in place of an old one, found in the flash image")
+                .args(&search_args())
+                .arg(
+                    Arg::with_name("old")
+                        .long("old")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The binary to replace, searched in the flash image"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The binary to write at the offset of the old one"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The modified flash image to write"),
+                )
+                .arg(
+                    Arg::with_name("fill")
+                        .long("fill")
+                        .takes_value(true)
+                        .default_value("0xff")
+                        .help("Byte written over the rest of the old binary"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check the flash image against the binaries expected at each offset, failing on any difference")
+                .arg(
+                    Arg::with_name("flash_image")
+                        .short("i")
+                        .long("image")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The flash image to verify"),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .short("m")
+                        .long("manifest")
+                        .required(true)
+                        .takes_value(true)
+                        .help("TOML manifest of the binary expected at each offset, and its allowed alternatives"),
+                )
+                .arg(
+                    Arg::with_name("bsize")
+                        .short("s")
+                        .long("size")
+                        .takes_value(true)
+                        .default_value("512")
+                        .help("Page / block size"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Draw the blocks differing from another flash image, along with the binaries and regions found")
+                .args(&search_args())
+                .arg(
+                    Arg::with_name("against")
+                        .long("against")
+                        .required(true)
+                        .takes_value(true)
+                        .help("The other flash image to compare with, e.g. a dump of another unit"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("consensus")
+                .about("Find the blocks common to the dumps of several units, and the per-unit ones")
+                .arg(
+                    Arg::with_name("images")
+                        .short("i")
+                        .long("images")
+                        .required(true)
+                        .takes_value(true)
+                        .multiple(true)
+                        .min_values(2)
+                        .help("The dumps of the units to compare"),
+                )
+                .arg(
+                    Arg::with_name("bsize")
+                        .short("s")
+                        .long("size")
+                        .takes_value(true)
+                        .default_value("512")
+                        .help("Page / block size"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("JSON file to write the common and per-unit regions to"),
+                )
+                .arg(
+                    Arg::with_name("v_scale")
+                        .long("v_scale")
+                        .takes_value(true)
+                        .help("Vertical scaling, default is half of the term size"),
+                )
+                .arg(
+                    Arg::with_name("h_scale")
+                        .long("h_scale")
+                        .takes_value(true)
+                        .help("Horizontal scaling, default is half of the term size"),
+                ),
         )
 }
@@ -0,0 +1,87 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use serde_json::json;
+
+/// A range of blocks of the dumps of several units of a product
+#[derive(Debug)]
+pub struct Span {
+    offset: usize,
+    size: usize,
+    /// whether the content differs between the units (serial numbers,
+    /// calibration...), or is common to all of them (firmware)
+    per_unit: bool,
+}
+
+impl Span {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.size
+    }
+
+    pub fn is_per_unit(&self) -> bool {
+        self.per_unit
+    }
+}
+
+/// Split the `size` bytes of the dumps into the ranges which vary between the
+/// units, as found by [`crate::seeker::varying_blocks`], and the ranges common
+/// to all of them
+pub fn split(varying: &[(usize, usize)], size: usize) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    for &(offset, len) in varying.iter() {
+        if offset > pos {
+            spans.push(Span {
+                offset: pos,
+                size: offset - pos,
+                per_unit: false,
+            });
+        }
+        spans.push(Span {
+            offset,
+            size: len,
+            per_unit: true,
+        });
+        pos = offset + len;
+    }
+    if pos < size {
+        spans.push(Span {
+            offset: pos,
+            size: size - pos,
+            per_unit: false,
+        });
+    }
+    spans
+}
+
+/// Describe the spans as JSON, for the tools processing the per-unit regions
+pub fn report(images: &[&str], block_size: usize, size: usize, spans: &[Span]) -> String {
+    let regions: Vec<_> = spans
+        .iter()
+        .map(|s| {
+            json!({
+                "kind": if s.per_unit { "per-unit" } else { "common" },
+                "offset": s.offset,
+                "size": s.size,
+            })
+        })
+        .collect();
+    let report = json!({
+        "images": images,
+        "block_size": block_size,
+        "size": size,
+        "regions": regions,
+    });
+    serde_json::to_string_pretty(&report).unwrap()
+}
@@ -11,14 +11,48 @@ use thiserror::Error;
 pub enum Error {
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
-    #[error("Cannot read file")]
-    ReadErr,
     #[error("Shape error: {0}")]
     Shape(#[from] ndarray::ShapeError),
     #[error("Free column not found")]
     FreeColNotFound,
     #[error("Bad shape")]
     BadShape,
+    #[error("Out of the flash image")]
+    OutOfImage,
+    #[error("Invalid device tree blob: {0}")]
+    InvalidFdt(&'static str),
+    #[error("No fixed partitions found in device tree")]
+    NoPartitions,
+    #[error("Invalid SquashFS filesystem: {0}")]
+    InvalidSquashfs(&'static str),
+    #[error("Decompression failed: {0}")]
+    Decompress(&'static str),
+    #[error("Invalid Android sparse image: {0}")]
+    InvalidSparse(&'static str),
+    #[error("Invalid {0} file, line {1}")]
+    InvalidRecord(&'static str, usize),
+    #[error("Invalid ELF file: {0}")]
+    InvalidElf(&'static str),
+    #[error("Invalid archive: {0}")]
+    InvalidArchive(&'static str),
+    #[error("Invalid glob pattern '{0}'")]
+    InvalidPattern(String),
+    #[error("No Intel Flash Descriptor found")]
+    NoIfd,
+    #[error("Invalid layout, line {0}: '{1}'")]
+    InvalidLayout(usize, String),
+    #[error("Invalid manifest, line {0}: '{1}'")]
+    InvalidManifest(usize, String),
+    #[error("Invalid manifest: {0}")]
+    InvalidToml(#[from] toml::de::Error),
+    #[error("'{0}' and '{1}' overlap")]
+    Overlap(String, String),
+    #[error("'{0}' does not fit in the image")]
+    DoesNotFit(String),
+    #[error("The block size must be greater than {0} bytes")]
+    BlockSize(usize),
+    #[error("Unknown format '{0}'")]
+    UnknownFormat(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,160 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use crate::puzzle::{PieceKind, PuzzlePiece};
+use serde_json::json;
+use std::{fs, path::Path};
+
+/// An area of the flash image written to its own file
+#[derive(Debug)]
+pub struct Carving {
+    file: String,
+    // None for the unidentified gaps
+    name: Option<String>,
+    // name of the piece holding this one, if any
+    parent: Option<String>,
+    kind: &'static str,
+    offset: usize,
+    size: usize,
+}
+
+impl Carving {
+    pub fn file(&self) -> &str {
+        self.file.as_str()
+    }
+}
+
+/// The areas of the flash image covered by none of the pieces, as
+/// (offset, size) couples
+pub fn gaps(pieces: &[PuzzlePiece], image_size: usize) -> Vec<(usize, usize)> {
+    let mut covered: Vec<(usize, usize)> = pieces
+        .iter()
+        .map(|p| (p.start(), std::cmp::min(p.start() + p.len(), image_size)))
+        .collect();
+    covered.sort();
+
+    let mut gaps = Vec::new();
+    let mut pos = 0;
+    for (start, end) in covered {
+        if start > pos {
+            gaps.push((pos, start - pos));
+        }
+        pos = std::cmp::max(pos, end);
+    }
+    if pos < image_size {
+        gaps.push((pos, image_size - pos));
+    }
+    gaps
+}
+
+// keep the file names portable: the piece names may be paths
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// file of an area, named by offset, length and piece name: the extension of
+// the piece name is dropped, the file gets its own
+fn file_name(offset: usize, size: usize, name: Option<&str>) -> String {
+    let stem = name.map_or("gap".to_string(), |name| {
+        sanitize(&Path::new(name).with_extension("").to_string_lossy())
+    });
+    format!("{:08x}-{:08x}-{}.bin", offset, size, stem)
+}
+
+/// Write each piece, and each gap between them, to its own file of
+/// `out_dir`, named by offset, length and piece name
+pub fn extract(data: &[u8], pieces: &[PuzzlePiece], out_dir: &Path) -> Result<Vec<Carving>> {
+    let mut carvings: Vec<Carving> = pieces
+        .iter()
+        .map(|p| Carving {
+            file: String::new(),
+            name: Some(p.name().to_string()),
+            parent: p.parent().map(|i| pieces[i].name().to_string()),
+            kind: match p.kind() {
+                PieceKind::Binary => "binary",
+                PieceKind::Region => "region",
+                PieceKind::Change => "change",
+            },
+            offset: p.start(),
+            size: p.len(),
+        })
+        .collect();
+    carvings.extend(
+        gaps(pieces, data.len())
+            .into_iter()
+            .map(|(offset, size)| Carving {
+                file: String::new(),
+                name: None,
+                parent: None,
+                kind: "gap",
+                offset,
+                size,
+            }),
+    );
+    carvings.sort_by_key(|c| (c.offset, c.size));
+
+    fs::create_dir_all(out_dir)?;
+    for carving in carvings.iter_mut() {
+        carving.file = file_name(carving.offset, carving.size, carving.name.as_deref());
+        let end = std::cmp::min(carving.offset + carving.size, data.len());
+        fs::write(out_dir.join(&carving.file), &data[carving.offset..end])?;
+    }
+    Ok(carvings)
+}
+
+/// JSON description of the extracted files
+pub fn index(image_name: &str, image_size: usize, carvings: &[Carving]) -> String {
+    let entries: Vec<_> = carvings
+        .iter()
+        .map(|c| {
+            json!({
+                "file": c.file,
+                "name": c.name,
+                "parent": c.parent,
+                "kind": c.kind,
+                "offset": c.offset,
+                "size": c.size,
+            })
+        })
+        .collect();
+    let index = json!({
+        "image": image_name,
+        "size": image_size,
+        "files": entries,
+    });
+    serde_json::to_string_pretty(&index).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names() {
+        assert_eq!(
+            file_name(0, 0x10000, Some("a.bin")),
+            "00000000-00010000-a.bin"
+        );
+        assert_eq!(
+            file_name(0x10000, 0x400, Some("out/rootfs.squashfs")),
+            "00010000-00000400-out_rootfs.bin"
+        );
+        assert_eq!(
+            file_name(0x10400, 0x200, Some("u-boot")),
+            "00010400-00000200-u-boot.bin"
+        );
+        assert_eq!(file_name(0x10600, 0x200, None), "00010600-00000200-gap.bin");
+    }
+}
@@ -0,0 +1,367 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{le16, le32};
+use crate::error::*;
+
+const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+const SPARSE_HEADER_SZ: usize = 28;
+const CHUNK_HEADER_SZ: usize = 12;
+
+const BOOT_MAGIC: &[u8] = b"ANDROID!";
+// page size of the boot images from version 3
+const BOOT_V3_PAGE_SZ: usize = 4096;
+
+/// Whether `data` is an Android sparse image
+pub fn is_sparse(data: &[u8]) -> bool {
+    le32(data, 0) == Some(SPARSE_MAGIC)
+}
+
+/// Expand an Android sparse image into the raw data it describes. The
+/// "don't care" chunks are filled with zeros.
+pub fn expand_sparse(data: &[u8]) -> Result<Vec<u8>> {
+    let field = |offset| le32(data, offset).ok_or(Error::InvalidSparse("truncated header"));
+    if field(0)? != SPARSE_MAGIC {
+        return Err(Error::InvalidSparse("bad magic"));
+    }
+    let blk_sz = field(12)? as usize;
+    let total_blks = field(16)? as usize;
+    let total_chunks = field(20)? as usize;
+    let file_hdr_sz = le16(data, 8).unwrap() as usize;
+    let chunk_hdr_sz = le16(data, 10).unwrap() as usize;
+    if blk_sz == 0
+        || !blk_sz.is_multiple_of(4)
+        || file_hdr_sz < SPARSE_HEADER_SZ
+        || chunk_hdr_sz < CHUNK_HEADER_SZ
+    {
+        return Err(Error::InvalidSparse("bad header"));
+    }
+    let total_sz = total_blks
+        .checked_mul(blk_sz)
+        .ok_or(Error::InvalidSparse("bad header"))?;
+
+    // the header is not trusted for the size: the output grows with the chunks
+    let mut out = Vec::new();
+    let mut pos = file_hdr_sz;
+    for _ in 0..total_chunks {
+        let chunk_type = le16(data, pos).ok_or(Error::InvalidSparse("truncated chunk"))?;
+        let chunk_sz = le32(data, pos + 4).ok_or(Error::InvalidSparse("truncated chunk"))? as usize;
+        let chunk_total =
+            le32(data, pos + 8).ok_or(Error::InvalidSparse("truncated chunk"))? as usize;
+        let body = pos
+            .checked_add(chunk_total)
+            .filter(|_| chunk_total >= chunk_hdr_sz)
+            .and_then(|end| data.get(pos + chunk_hdr_sz..end))
+            .ok_or(Error::InvalidSparse("truncated chunk"))?;
+        let out_sz = chunk_sz
+            .checked_mul(blk_sz)
+            .filter(|&out_sz| out_sz <= total_sz - out.len())
+            .ok_or(Error::InvalidSparse("chunks larger than the image"))?;
+        match chunk_type {
+            CHUNK_RAW => {
+                if body.len() != out_sz {
+                    return Err(Error::InvalidSparse("bad raw chunk size"));
+                }
+                out.extend_from_slice(body);
+            }
+            CHUNK_FILL => {
+                let pattern = body
+                    .get(..4)
+                    .ok_or(Error::InvalidSparse("bad fill chunk"))?;
+                for _ in 0..out_sz / 4 {
+                    out.extend_from_slice(pattern);
+                }
+            }
+            CHUNK_DONT_CARE => out.resize(out.len() + out_sz, 0),
+            CHUNK_CRC32 => (),
+            _ => return Err(Error::InvalidSparse("unknown chunk type")),
+        }
+        pos += chunk_total;
+    }
+    Ok(out)
+}
+
+/// A part of an Android boot image
+#[derive(Debug)]
+pub struct Section {
+    name: &'static str,
+    offset: usize,
+    size: usize,
+}
+
+impl Section {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Offset in the flash image
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// An Android boot image (`ANDROID!` header) found in the flash image
+#[derive(Debug)]
+pub struct BootImage {
+    offset: usize,
+    size: usize,
+    version: u32,
+    page_size: usize,
+    sections: Vec<Section>,
+}
+
+impl BootImage {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Version of the boot image header
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// The non-empty sections following the header, in order
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Parse the boot image header at `offset` of the flash image
+    pub fn parse(data: &[u8], offset: usize) -> Option<Self> {
+        let hdr = data.get(offset..)?;
+        if !hdr.starts_with(BOOT_MAGIC) {
+            return None;
+        }
+        let version = le32(hdr, 40)?;
+        let u32_at = |o| le32(hdr, o).map(|v| v as usize);
+
+        // sizes of the header and of each section, in order
+        let (page_size, parts) = match version {
+            0..=2 => {
+                let page_size = u32_at(36)?;
+                let mut parts = vec![
+                    ("header", page_size),
+                    ("kernel", u32_at(8)?),
+                    ("ramdisk", u32_at(16)?),
+                    ("second", u32_at(24)?),
+                ];
+                if version >= 1 {
+                    parts.push(("recovery_dtbo", u32_at(1632)?));
+                }
+                if version == 2 {
+                    parts.push(("dtb", u32_at(1648)?));
+                }
+                (page_size, parts)
+            }
+            3 | 4 => {
+                let mut parts = vec![
+                    ("header", BOOT_V3_PAGE_SZ),
+                    ("kernel", u32_at(8)?),
+                    ("ramdisk", u32_at(12)?),
+                ];
+                if version == 4 {
+                    parts.push(("signature", u32_at(1580)?));
+                }
+                (BOOT_V3_PAGE_SZ, parts)
+            }
+            _ => return None,
+        };
+        if !page_size.is_power_of_two() || !(2048..=16384).contains(&page_size) {
+            return None;
+        }
+
+        // every section starts on a page boundary
+        let mut sections = Vec::new();
+        let mut pos = offset;
+        for (name, size) in parts {
+            if size > 0 && name != "header" {
+                sections.push(Section {
+                    name,
+                    offset: pos,
+                    size,
+                });
+            }
+            pos = pos.checked_add(size.div_ceil(page_size) * page_size)?;
+        }
+        if pos > data.len() {
+            return None;
+        }
+        Some(BootImage {
+            offset,
+            size: pos - offset,
+            version,
+            page_size,
+            sections,
+        })
+    }
+}
+
+/// Find every Android boot image of the flash image
+pub fn scan(data: &[u8]) -> Vec<BootImage> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+    // boot images are at least aligned on the smallest page size
+    while pos + BOOT_MAGIC.len() <= data.len() {
+        match BootImage::parse(data, pos) {
+            Some(boot) => {
+                pos += boot.size();
+                found.push(boot);
+            }
+            None => pos += 2048,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLK_SZ: usize = 4096;
+
+    fn chunk(chunk_type: u16, blocks: usize, body: &[u8]) -> Vec<u8> {
+        let mut c = chunk_type.to_le_bytes().to_vec();
+        c.extend([0, 0]);
+        c.extend((blocks as u32).to_le_bytes());
+        c.extend(((CHUNK_HEADER_SZ + body.len()) as u32).to_le_bytes());
+        c.extend_from_slice(body);
+        c
+    }
+
+    fn sparse(total_blks: usize, chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut s = SPARSE_MAGIC.to_le_bytes().to_vec();
+        // version 1.0
+        s.extend([1, 0, 0, 0]);
+        s.extend((SPARSE_HEADER_SZ as u16).to_le_bytes());
+        s.extend((CHUNK_HEADER_SZ as u16).to_le_bytes());
+        s.extend((BLK_SZ as u32).to_le_bytes());
+        s.extend((total_blks as u32).to_le_bytes());
+        s.extend((chunks.len() as u32).to_le_bytes());
+        s.extend([0; 4]);
+        for c in chunks {
+            s.extend(c);
+        }
+        s
+    }
+
+    #[test]
+    fn expand() {
+        let raw: Vec<u8> = (0..BLK_SZ).map(|n| n as u8).collect();
+        let image = sparse(
+            5,
+            &[
+                chunk(CHUNK_RAW, 1, &raw),
+                chunk(CHUNK_FILL, 2, &[0xde, 0xad, 0xbe, 0xef]),
+                chunk(CHUNK_DONT_CARE, 1, &[]),
+                chunk(CHUNK_CRC32, 0, &[0; 4]),
+                chunk(CHUNK_RAW, 1, &raw),
+            ],
+        );
+        assert!(is_sparse(&image));
+        let out = expand_sparse(&image).unwrap();
+        assert_eq!(out.len(), 5 * BLK_SZ);
+        assert_eq!(out[..BLK_SZ], raw);
+        assert!(out[BLK_SZ..3 * BLK_SZ]
+            .chunks(4)
+            .all(|c| c == [0xde, 0xad, 0xbe, 0xef]));
+        assert!(out[3 * BLK_SZ..4 * BLK_SZ].iter().all(|&b| b == 0));
+        assert_eq!(out[4 * BLK_SZ..], raw);
+    }
+
+    #[test]
+    fn bad_sparse() {
+        let fill = chunk(CHUNK_FILL, 1, &[0; 4]);
+        assert!(!is_sparse(b"\x3a\xff\x26\xec"));
+        // header sizes smaller than the headers
+        let mut image = sparse(1, std::slice::from_ref(&fill));
+        image[8] = 12;
+        assert!(expand_sparse(&image).is_err());
+        let mut image = sparse(1, std::slice::from_ref(&fill));
+        image[10] = 8;
+        assert!(expand_sparse(&image).is_err());
+        // a huge image announced, made of a single block
+        let image = sparse(0xffff_ffff, std::slice::from_ref(&fill));
+        assert_eq!(expand_sparse(&image).unwrap().len(), BLK_SZ);
+        // chunks larger than the image, or than the file
+        assert!(expand_sparse(&sparse(1, &[fill.clone(), fill.clone()])).is_err());
+        assert!(expand_sparse(&sparse(1, &[chunk(CHUNK_DONT_CARE, 0xffff_ffff, &[])])).is_err());
+        let image = sparse(1, &[chunk(CHUNK_RAW, 1, &[0; BLK_SZ])]);
+        assert!(expand_sparse(&image[..image.len() - 1]).is_err());
+        // raw chunk of the wrong size
+        assert!(expand_sparse(&sparse(1, &[chunk(CHUNK_RAW, 1, &[0; 16])])).is_err());
+    }
+
+    // boot image header of version 0 or 2, with the given section sizes
+    fn boot_v0(version: u32, page_size: usize, sizes: &[usize]) -> Vec<u8> {
+        let mut hdr = vec![0; page_size];
+        hdr[..8].copy_from_slice(BOOT_MAGIC);
+        for (&size, field) in sizes.iter().zip([8, 16, 24, 1632, 1648]) {
+            hdr[field..field + 4].copy_from_slice(&(size as u32).to_le_bytes());
+        }
+        hdr[36..40].copy_from_slice(&(page_size as u32).to_le_bytes());
+        hdr[40..44].copy_from_slice(&version.to_le_bytes());
+        hdr
+    }
+
+    #[test]
+    fn boot_images() {
+        let mut data = vec![0xff; 2048];
+        data.extend(boot_v0(0, 2048, &[3000, 100, 0]));
+        data.resize(2048 + 4 * 2048, 0);
+        let mut v3 = vec![0; BOOT_V3_PAGE_SZ];
+        v3[..8].copy_from_slice(BOOT_MAGIC);
+        v3[8..12].copy_from_slice(&5000u32.to_le_bytes());
+        v3[40..44].copy_from_slice(&3u32.to_le_bytes());
+        data.extend(v3);
+        data.resize(data.len() + 2 * BOOT_V3_PAGE_SZ, 0);
+
+        let found = scan(&data);
+        assert_eq!(found.len(), 2);
+        let sections: Vec<(&str, usize, usize)> = found[0]
+            .sections()
+            .iter()
+            .map(|s| (s.name(), s.offset(), s.size()))
+            .collect();
+        assert_eq!(
+            (found[0].offset(), found[0].size(), found[0].version()),
+            (2048, 4 * 2048, 0)
+        );
+        assert_eq!(sections, [("kernel", 4096, 3000), ("ramdisk", 8192, 100)]);
+        assert_eq!((found[1].offset(), found[1].size()), (5 * 2048, 3 * 4096));
+        assert_eq!(found[1].page_size(), BOOT_V3_PAGE_SZ);
+        assert_eq!(found[1].sections()[0].offset(), 5 * 2048 + 4096);
+    }
+
+    #[test]
+    fn bad_boot_images() {
+        // sections past the end of the image
+        let data = boot_v0(2, 2048, &[3000, 100, 0, 0, 0x1000]);
+        assert!(BootImage::parse(&data, 0).is_none());
+        // bad page size
+        let mut data = boot_v0(0, 2048, &[]);
+        data[36..40].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(BootImage::parse(&data, 0).is_none());
+        // unknown version
+        let data = boot_v0(9, 2048, &[]);
+        assert!(BootImage::parse(&data, 0).is_none());
+        assert!(scan(&[0; 0x2000]).is_empty());
+    }
+}
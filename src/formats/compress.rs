@@ -0,0 +1,204 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::lzo;
+use crate::error::*;
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+use std::{
+    fmt,
+    io::{BufReader, Read},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zlib,
+    Lzma,
+    Xz,
+    Lzo,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Compression::Gzip => "gzip",
+            Compression::Zlib => "zlib",
+            Compression::Lzma => "lzma",
+            Compression::Xz => "xz",
+            Compression::Lzo => "lzo",
+            Compression::Zstd => "zstd",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// read the whole stream, without going past 'max_size'
+fn read_bounded<R: Read>(reader: R, max_size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    reader
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|_| Error::Decompress("corrupted stream"))?;
+    if out.len() > max_size {
+        return Err(Error::Decompress("output overrun"));
+    }
+    Ok(out)
+}
+
+/// Decompress `input`, which is expected to hold at most `max_size` bytes
+pub fn decompress(compression: Compression, input: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    match compression {
+        Compression::Gzip => read_bounded(MultiGzDecoder::new(input), max_size),
+        Compression::Zlib => read_bounded(ZlibDecoder::new(input), max_size),
+        Compression::Lzma | Compression::Xz => {
+            let mut reader = BufReader::new(input);
+            let mut out = Vec::new();
+            let res = if compression == Compression::Lzma {
+                lzma_rs::lzma_decompress(&mut reader, &mut out)
+            } else {
+                lzma_rs::xz_decompress(&mut reader, &mut out)
+            };
+            res.map_err(|_| Error::Decompress("corrupted stream"))?;
+            if out.len() > max_size {
+                return Err(Error::Decompress("output overrun"));
+            }
+            Ok(out)
+        }
+        Compression::Lzo => lzo::decompress(input, max_size),
+        Compression::Zstd => {
+            let decoder = ruzstd::StreamingDecoder::new(input)
+                .map_err(|_| Error::Decompress("corrupted stream"))?;
+            read_bounded(decoder, max_size)
+        }
+    }
+}
+
+/// Identify the compressed files by their magic number. Only the formats
+/// whose header carries a magic number can be detected.
+pub fn detect(data: &[u8]) -> Option<Compression> {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        Some(Compression::Gzip)
+    } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Some(Compression::Xz)
+    } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+/// Decompress a whole stream of unknown size, such as a compressed file
+pub fn decompress_stream<R: Read>(compression: Compression, reader: R) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut reader = BufReader::new(reader);
+    let ok = match compression {
+        Compression::Gzip => MultiGzDecoder::new(reader).read_to_end(&mut out).is_ok(),
+        Compression::Zlib => ZlibDecoder::new(reader).read_to_end(&mut out).is_ok(),
+        Compression::Lzma => lzma_rs::lzma_decompress(&mut reader, &mut out).is_ok(),
+        Compression::Xz => lzma_rs::xz_decompress(&mut reader, &mut out).is_ok(),
+        Compression::Zstd => match ruzstd::StreamingDecoder::new(&mut reader) {
+            Ok(mut decoder) => decoder.read_to_end(&mut out).is_ok(),
+            Err(_) => false,
+        },
+        Compression::Lzo => return Err(Error::Decompress("LZO streams are not supported")),
+    };
+    if !ok {
+        return Err(Error::Decompress("corrupted stream"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression as Level,
+    };
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"imgseek imgseek imgseek imgseek imgseek imgseek imgseek";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        lzma_rs::xz_compress(&mut BufReader::new(data), &mut out).unwrap();
+        out
+    }
+
+    // zstd frame holding a single raw block
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd];
+        // single segment, with a 1 byte content size
+        frame.extend([0x20, data.len() as u8]);
+        let block_header = ((data.len() as u32) << 3) | 1;
+        frame.extend(&block_header.to_le_bytes()[..3]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn detect_files() {
+        assert_eq!(detect(&gzip(TEXT)), Some(Compression::Gzip));
+        assert_eq!(detect(&xz(TEXT)), Some(Compression::Xz));
+        assert_eq!(detect(&zstd(TEXT)), Some(Compression::Zstd));
+        assert_eq!(detect(TEXT), None);
+    }
+
+    #[test]
+    fn streams() {
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let compressed = match compression {
+                Compression::Gzip => gzip(TEXT),
+                Compression::Xz => xz(TEXT),
+                _ => zstd(TEXT),
+            };
+            let out = decompress_stream(compression, compressed.as_slice()).unwrap();
+            assert_eq!(out, TEXT, "{}", compression);
+            let truncated = &compressed[..compressed.len() / 2];
+            assert!(
+                decompress_stream(compression, truncated).is_err(),
+                "{}",
+                compression
+            );
+        }
+        // two gzip members, as written by concatenating gzip files
+        let mut members = gzip(b"imgseek ");
+        members.extend(gzip(b"imgseek"));
+        let out = decompress_stream(Compression::Gzip, members.as_slice()).unwrap();
+        assert_eq!(out, b"imgseek imgseek");
+        assert!(decompress_stream(Compression::Lzo, TEXT).is_err());
+    }
+
+    #[test]
+    fn bounded() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(TEXT).unwrap();
+        let zlib = encoder.finish().unwrap();
+        let mut lzma = Vec::new();
+        lzma_rs::lzma_compress(&mut BufReader::new(TEXT), &mut lzma).unwrap();
+
+        let inputs = [
+            (Compression::Gzip, gzip(TEXT)),
+            (Compression::Zlib, zlib),
+            (Compression::Lzma, lzma),
+            (Compression::Xz, xz(TEXT)),
+            (Compression::Zstd, zstd(TEXT)),
+        ];
+        for (compression, input) in inputs.iter() {
+            let out = decompress(*compression, input, TEXT.len()).unwrap();
+            assert_eq!(out, TEXT, "{}", compression);
+            // the output does not fit
+            assert!(decompress(*compression, input, TEXT.len() - 1).is_err());
+        }
+    }
+}
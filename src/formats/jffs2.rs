@@ -0,0 +1,473 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::compress::{self, Compression};
+use crate::error::*;
+use std::collections::HashMap;
+
+const JFFS2_MAGIC: u16 = 0x1985;
+const NODE_HEADER_SZ: usize = 12;
+const NODE_ACCURATE: u16 = 0x2000;
+const NODETYPE_DIRENT: u16 = 0xe001;
+const NODETYPE_INODE: u16 = 0xe002;
+
+const RAW_INODE_SZ: usize = 68;
+const RAW_DIRENT_SZ: usize = 40;
+
+const COMPR_NONE: u8 = 0x00;
+const COMPR_ZERO: u8 = 0x01;
+const COMPR_RTIME: u8 = 0x02;
+const COMPR_ZLIB: u8 = 0x06;
+const COMPR_LZO: u8 = 0x07;
+
+const ROOT_INO: u32 = 1;
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+
+// a directory cannot be nested deeper than this (loop protection)
+const MAX_DEPTH: usize = 256;
+
+/// CRC used by JFFS2: CRC-32 without the initial and final inversions
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(data);
+    !hasher.finalize()
+}
+
+// JFFS2 is written with the endianness of its host
+#[derive(Debug, Clone, Copy)]
+struct Endian(bool);
+
+impl Endian {
+    fn u16(&self, data: &[u8], offset: usize) -> Option<u16> {
+        let b = data.get(offset..offset + 2)?;
+        Some(if self.0 {
+            u16::from_be_bytes([b[0], b[1]])
+        } else {
+            u16::from_le_bytes([b[0], b[1]])
+        })
+    }
+
+    fn u32(&self, data: &[u8], offset: usize) -> Option<u32> {
+        let b = data.get(offset..offset + 4)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if self.0 {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+}
+
+// A piece of file content, stored in a data node
+#[derive(Debug)]
+struct DataNode {
+    version: u32,
+    // position in the file
+    offset: usize,
+    dsize: usize,
+    // position of the (compressed) data in the flash image
+    data_pos: usize,
+    csize: usize,
+    compr: u8,
+}
+
+/// A regular file of the filesystem, rebuilt from its nodes
+#[derive(Debug)]
+pub struct File {
+    path: String,
+    size: usize,
+    nodes: Vec<DataNode>,
+}
+
+impl File {
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// A JFFS2 filesystem found in the flash image
+#[derive(Debug)]
+pub struct Jffs2 {
+    offset: usize,
+    size: usize,
+    big_endian: bool,
+    nodes: usize,
+    bad_crc: usize,
+    files: Vec<File>,
+}
+
+// inode being rebuilt: its latest metadata, and its data nodes
+#[derive(Debug, Default)]
+struct Inode {
+    version: u32,
+    mode: u32,
+    isize: usize,
+    nodes: Vec<DataNode>,
+}
+
+// latest directory entry of a (parent, name) couple
+#[derive(Debug)]
+struct Dirent {
+    version: u32,
+    ino: u32,
+}
+
+#[derive(Debug, Default)]
+struct Builder {
+    inodes: HashMap<u32, Inode>,
+    dirents: HashMap<(u32, String), Dirent>,
+    nodes: usize,
+    bad_crc: usize,
+}
+
+impl Builder {
+    // check the node at 'pos', and record it; return its length if the
+    // header is valid
+    fn node(&mut self, data: &[u8], pos: usize, endian: Endian) -> Option<usize> {
+        let magic = endian.u16(data, pos)?;
+        let nodetype = endian.u16(data, pos + 2)?;
+        let totlen = endian.u32(data, pos + 4)? as usize;
+        let hdr_crc = endian.u32(data, pos + 8)?;
+        if magic != JFFS2_MAGIC || totlen < NODE_HEADER_SZ || pos + totlen > data.len() {
+            return None;
+        }
+        // obsolete nodes have their 'accurate' bit cleared after the CRC
+        // computation
+        let mut header = data[pos..pos + 8].to_vec();
+        let accurate = if endian.0 {
+            (nodetype | NODE_ACCURATE).to_be_bytes()
+        } else {
+            (nodetype | NODE_ACCURATE).to_le_bytes()
+        };
+        header[2..4].copy_from_slice(&accurate);
+        if crc32(&header) != hdr_crc {
+            return None;
+        }
+        self.nodes += 1;
+
+        let node = &data[pos..pos + totlen];
+        if nodetype & NODE_ACCURATE != 0 && !self.record(node, pos, nodetype, endian) {
+            self.bad_crc += 1;
+        }
+        Some(totlen)
+    }
+
+    // record an inode or a directory entry, return false on bad CRC
+    fn record(&mut self, node: &[u8], pos: usize, nodetype: u16, endian: Endian) -> bool {
+        let u32_at = |offset| endian.u32(node, offset).unwrap_or(0);
+        match nodetype {
+            NODETYPE_INODE if node.len() >= RAW_INODE_SZ => {
+                if crc32(&node[..RAW_INODE_SZ - 8]) != u32_at(64) {
+                    return false;
+                }
+                let csize = u32_at(48) as usize;
+                let compr_data = match node.get(RAW_INODE_SZ..RAW_INODE_SZ + csize) {
+                    Some(d) => d,
+                    None => return false,
+                };
+                if crc32(compr_data) != u32_at(60) {
+                    return false;
+                }
+                let version = u32_at(16);
+                let inode = self.inodes.entry(u32_at(12)).or_default();
+                if version >= inode.version {
+                    inode.version = version;
+                    inode.mode = u32_at(20);
+                    inode.isize = u32_at(28) as usize;
+                }
+                let dsize = u32_at(52) as usize;
+                if dsize > 0 {
+                    inode.nodes.push(DataNode {
+                        version,
+                        offset: u32_at(44) as usize,
+                        dsize,
+                        data_pos: pos + RAW_INODE_SZ,
+                        csize,
+                        compr: node[56],
+                    });
+                }
+                true
+            }
+            NODETYPE_DIRENT if node.len() >= RAW_DIRENT_SZ => {
+                if crc32(&node[..RAW_DIRENT_SZ - 8]) != u32_at(32) {
+                    return false;
+                }
+                let nsize = node[28] as usize;
+                let name = match node.get(RAW_DIRENT_SZ..RAW_DIRENT_SZ + nsize) {
+                    Some(name) => name,
+                    None => return false,
+                };
+                if crc32(name) != u32_at(36) {
+                    return false;
+                }
+                let key = (u32_at(12), String::from_utf8_lossy(name).to_string());
+                let dirent = Dirent {
+                    version: u32_at(16),
+                    ino: u32_at(20),
+                };
+                match self.dirents.get(&key) {
+                    Some(d) if d.version > dirent.version => (),
+                    _ => {
+                        self.dirents.insert(key, dirent);
+                    }
+                }
+                true
+            }
+            // clean markers, padding, summaries...
+            _ => true,
+        }
+    }
+
+    // walk the directory tree from the root, and collect the regular files
+    fn files(mut self) -> Vec<File> {
+        let mut children: HashMap<u32, Vec<(String, u32)>> = HashMap::new();
+        // ino 0 marks a deleted entry
+        for ((pino, name), dirent) in self.dirents.iter().filter(|(_, d)| d.ino != 0) {
+            children
+                .entry(*pino)
+                .or_default()
+                .push((name.clone(), dirent.ino));
+        }
+        let mut files = Vec::new();
+        let mut stack = vec![(ROOT_INO, String::new(), 0)];
+        while let Some((ino, path, depth)) = stack.pop() {
+            if depth > MAX_DEPTH {
+                continue;
+            }
+            for (name, child) in children.get(&ino).into_iter().flatten() {
+                let child_path = format!("{}/{}", path, name);
+                let mode = self.inodes.get(child).map(|i| i.mode & S_IFMT);
+                match mode {
+                    Some(S_IFDIR) => stack.push((*child, child_path, depth + 1)),
+                    Some(S_IFREG) => {
+                        let inode = self.inodes.remove(child).unwrap();
+                        files.push(File {
+                            path: child_path,
+                            size: inode.isize,
+                            nodes: inode.nodes,
+                        });
+                    }
+                    _ => (),
+                }
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+}
+
+// decompress the 'rtime' format: (byte, repeat count) couples, the repeat
+// being a copy from the last occurrence of the byte
+fn rtime_decompress(input: &[u8], dsize: usize) -> Result<Vec<u8>> {
+    let mut positions = [0usize; 256];
+    let mut out = Vec::with_capacity(dsize);
+    let mut pairs = input.chunks_exact(2);
+    while out.len() < dsize {
+        let pair = pairs
+            .next()
+            .ok_or(Error::Decompress("rtime: input overrun"))?;
+        let (value, repeat) = (pair[0], pair[1] as usize);
+        out.push(value);
+        let backoffs = positions[value as usize];
+        positions[value as usize] = out.len();
+        // the copy may overlap its own output
+        for i in backoffs..backoffs + repeat {
+            let b = out[i];
+            out.push(b);
+        }
+    }
+    out.truncate(dsize);
+    Ok(out)
+}
+
+impl Jffs2 {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// Number of valid nodes
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    /// Number of nodes with a bad node or data CRC
+    pub fn bad_crc(&self) -> usize {
+        self.bad_crc
+    }
+
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+
+    /// Content of a file, rebuilt from its data nodes in version order
+    pub fn read(&self, data: &[u8], file: &File) -> Result<Vec<u8>> {
+        let mut content = vec![0u8; file.size];
+        let mut nodes: Vec<&DataNode> = file.nodes.iter().collect();
+        nodes.sort_by_key(|n| n.version);
+        for node in nodes {
+            let input = &data[node.data_pos..node.data_pos + node.csize];
+            let unpacked = match node.compr {
+                COMPR_NONE => input.to_vec(),
+                COMPR_ZERO => vec![0u8; node.dsize],
+                COMPR_RTIME => rtime_decompress(input, node.dsize)?,
+                COMPR_ZLIB => compress::decompress(Compression::Zlib, input, node.dsize)?,
+                COMPR_LZO => compress::decompress(Compression::Lzo, input, node.dsize)?,
+                _ => return Err(Error::Decompress("unsupported JFFS2 compression")),
+            };
+            // the file may have been truncated by a later node
+            if node.offset < content.len() {
+                let end = std::cmp::min(node.offset + unpacked.len(), content.len());
+                content[node.offset..end].copy_from_slice(&unpacked[..end - node.offset]);
+            }
+        }
+        Ok(content)
+    }
+}
+
+// the gap between two nodes of the same filesystem is only made of erased
+// flash, or of garbage smaller than an erase block
+fn is_gap(data: &[u8], erase_size: usize) -> bool {
+    data.len() < erase_size || data.iter().all(|&b| b == 0xff)
+}
+
+/// Find every JFFS2 filesystem of the flash image. Their bounds are aligned
+/// on the erase block size.
+pub fn scan(data: &[u8], erase_size: usize) -> Vec<Jffs2> {
+    let mut found = Vec::new();
+    let mut current: Option<(Builder, Endian, usize, usize)> = None;
+    let mut pos = 0;
+
+    // nodes are 4-bytes aligned
+    while pos + NODE_HEADER_SZ <= data.len() {
+        let endian = match u16::from_le_bytes([data[pos], data[pos + 1]]) {
+            JFFS2_MAGIC => Endian(false),
+            m if m == JFFS2_MAGIC.swap_bytes() => Endian(true),
+            _ => {
+                pos += 4;
+                continue;
+            }
+        };
+
+        // a node far from the previous one starts a new filesystem
+        if let Some((_, e, _, end)) = &current {
+            if e.0 != endian.0 || !is_gap(&data[*end..pos], erase_size) {
+                let (builder, e, start, end) = current.take().unwrap();
+                found.push(Jffs2::finish(
+                    builder,
+                    e,
+                    start,
+                    end,
+                    erase_size,
+                    data.len(),
+                ));
+            }
+        }
+        let (builder, _, _, end) =
+            current.get_or_insert_with(|| (Builder::default(), endian, pos, pos));
+        match builder.node(data, pos, endian) {
+            Some(totlen) => {
+                pos += totlen.div_ceil(4) * 4;
+                *end = pos;
+            }
+            None => pos += 4,
+        }
+    }
+    if let Some((builder, e, start, end)) = current {
+        found.push(Jffs2::finish(
+            builder,
+            e,
+            start,
+            end,
+            erase_size,
+            data.len(),
+        ));
+    }
+
+    found.into_iter().filter(|fs| fs.nodes > 0).collect()
+}
+
+impl Jffs2 {
+    fn finish(
+        builder: Builder,
+        endian: Endian,
+        start: usize,
+        end: usize,
+        erase_size: usize,
+        data_len: usize,
+    ) -> Self {
+        let offset = start - start % erase_size;
+        let end = std::cmp::min(end.div_ceil(erase_size) * erase_size, data_len);
+        let nodes = builder.nodes;
+        let bad_crc = builder.bad_crc;
+        Jffs2 {
+            offset,
+            size: end - offset,
+            big_endian: endian.0,
+            nodes,
+            bad_crc,
+            files: builder.files(),
+        }
+    }
+}
+
+/// Find the files whose content is `binary`, among the filesystems found in
+/// the flash image `data`
+pub fn find<'a>(
+    filesystems: &'a [Jffs2],
+    data: &[u8],
+    binary: &[u8],
+) -> Vec<(&'a Jffs2, &'a File)> {
+    let mut found = Vec::new();
+    for fs in filesystems.iter() {
+        // only rebuild the files of the right size
+        for file in fs.files().iter().filter(|f| f.size() == binary.len()) {
+            if let Ok(content) = fs.read(data, file) {
+                if content == binary {
+                    found.push((fs, file));
+                }
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtime() {
+        // no repeat
+        assert_eq!(rtime_decompress(b"a\x00b\x00c\x00", 3).unwrap(), b"abc");
+        // a first byte is repeated from the start of the output
+        assert_eq!(rtime_decompress(b"a\x03", 4).unwrap(), b"aaaa");
+        // the repeat copies what followed the last occurrence of the byte
+        let out = rtime_decompress(b"a\x00b\x00a\x02", 5).unwrap();
+        assert_eq!(out, b"ababa");
+        // the output is cut to the expected size
+        assert_eq!(rtime_decompress(b"a\x09", 4).unwrap(), b"aaaa");
+    }
+
+    #[test]
+    fn rtime_truncated() {
+        assert!(rtime_decompress(b"a\x00b", 2).is_err());
+        assert!(rtime_decompress(b"a\x01", 4).is_err());
+    }
+}
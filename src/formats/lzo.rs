@@ -0,0 +1,202 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+
+// LZO1X decompression, following the 'safe' decompressor of the Linux kernel
+// (lib/lzo/lzo1x_decompress_safe.c)
+
+use crate::error::*;
+
+const M2_MAX_OFFSET: usize = 0x0800;
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Input<'_> {
+    fn byte(&mut self) -> Result<usize> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or(Error::Decompress("lzo: input overrun"))?;
+        self.pos += 1;
+        Ok(b as usize)
+    }
+
+    fn le16(&mut self) -> Result<usize> {
+        Ok(self.byte()? | self.byte()? << 8)
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or(Error::Decompress("lzo: input overrun"))
+    }
+
+    // lengths bigger than their opcode bits are encoded as a run of zeroes
+    fn long_length(&mut self, base: usize) -> Result<usize> {
+        let mut t = 0;
+        while self.peek()? == 0 {
+            t += 255;
+            self.pos += 1;
+        }
+        Ok(t + base + self.byte()?)
+    }
+
+    fn literals(&mut self, out: &mut Vec<u8>, len: usize) -> Result<()> {
+        let run = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(Error::Decompress("lzo: input overrun"))?;
+        out.extend_from_slice(run);
+        self.pos += len;
+        Ok(())
+    }
+}
+
+// copy 'len' bytes located 'distance' bytes behind the end of the output
+fn copy_match(out: &mut Vec<u8>, distance: usize, len: usize, max_size: usize) -> Result<()> {
+    if distance == 0 || distance > out.len() {
+        return Err(Error::Decompress("lzo: lookbehind overrun"));
+    }
+    if out.len() + len > max_size {
+        return Err(Error::Decompress("lzo: output overrun"));
+    }
+    let start = out.len() - distance;
+    // the source and destination may overlap: copy byte per byte
+    for n in 0..len {
+        out.push(out[start + n]);
+    }
+    Ok(())
+}
+
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let mut input = Input { data, pos: 0 };
+    let mut out: Vec<u8> = Vec::new();
+    // number of literals copied after the last instruction
+    let mut state: usize;
+
+    if data.len() < 3 {
+        return Err(Error::Decompress("lzo: input too short"));
+    }
+    if data[0] > 17 {
+        input.pos = 1;
+        let t = data[0] as usize - 17;
+        input.literals(&mut out, t)?;
+        state = if t < 4 { t } else { 4 };
+    } else {
+        state = 0;
+    }
+
+    loop {
+        let mut t = input.byte()?;
+        let distance;
+        let next;
+        if t < 16 {
+            if state == 0 {
+                // literal run
+                let len = if t == 0 { input.long_length(15)? } else { t } + 3;
+                input.literals(&mut out, len)?;
+                state = 4;
+                continue;
+            } else if state != 4 {
+                // 2 bytes match, after a short literal run
+                next = t & 3;
+                distance = 1 + (t >> 2) + (input.byte()? << 2);
+                t = 2;
+            } else {
+                // 3 bytes match, after a long literal run
+                next = t & 3;
+                distance = 1 + M2_MAX_OFFSET + (t >> 2) + (input.byte()? << 2);
+                t = 3;
+            }
+        } else if t >= 64 {
+            next = t & 3;
+            distance = 1 + ((t >> 2) & 7) + (input.byte()? << 3);
+            t = (t >> 5) + 1;
+        } else if t >= 32 {
+            t &= 31;
+            t = if t == 0 { input.long_length(31)? } else { t } + 2;
+            let ds = input.le16()?;
+            distance = 1 + (ds >> 2);
+            next = ds & 3;
+        } else {
+            let high = (t & 8) << 11;
+            t &= 7;
+            t = if t == 0 { input.long_length(7)? } else { t } + 2;
+            let ds = input.le16()?;
+            // end of stream marker
+            if high + (ds >> 2) == 0 {
+                break;
+            }
+            distance = high + (ds >> 2) + 0x4000;
+            next = ds & 3;
+        }
+        copy_match(&mut out, distance, t, max_size)?;
+
+        state = next;
+        input.literals(&mut out, next)?;
+    }
+
+    if out.len() > max_size {
+        return Err(Error::Decompress("lzo: output overrun"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // streams assembled by hand, each ended by the end of stream marker
+    #[test]
+    fn literals() {
+        let stream = b"\x16hello\x11\x00\x00";
+        assert_eq!(decompress(stream, 64).unwrap(), b"hello");
+        // literal run instruction, when the first byte is not a literal run
+        let stream = b"\x02hello\x11\x00\x00";
+        assert_eq!(decompress(stream, 64).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn long_literal_run() {
+        // 15 + 1 + 3 literals, the length being encoded after a zero
+        let mut stream = vec![0x00, 0x01];
+        stream.extend_from_slice(b"0123456789abcdefghi");
+        stream.extend_from_slice(b"\x11\x00\x00");
+        assert_eq!(decompress(&stream, 64).unwrap(), b"0123456789abcdefghi");
+    }
+
+    #[test]
+    fn matches() {
+        // M1: 2 bytes at distance 2, after a short literal run
+        let stream = b"\x14abc\x04\x00\x11\x00\x00";
+        assert_eq!(decompress(stream, 64).unwrap(), b"abcbc");
+        // M2: 4 bytes at distance 4
+        let stream = b"\x15abcd\x6c\x00\x11\x00\x00";
+        assert_eq!(decompress(stream, 64).unwrap(), b"abcdabcd");
+        // M3: 9 bytes at distance 3, overlapping its own output
+        let stream = b"\x14abc\x27\x08\x00\x11\x00\x00";
+        assert_eq!(decompress(stream, 64).unwrap(), b"abcabcabcabc");
+        // M2 followed by 2 literals, given by the low bits of the match
+        let stream = b"\x15abcd\x6e\x00xy\x11\x00\x00";
+        assert_eq!(decompress(stream, 64).unwrap(), b"abcdabcdxy");
+    }
+
+    #[test]
+    fn errors() {
+        // the match goes back before the start of the output
+        let stream = b"\x14abc\x27\x40\x00\x11\x00\x00";
+        assert!(decompress(stream, 64).is_err());
+        // the output is bigger than expected
+        let stream = b"\x14abc\x27\x08\x00\x11\x00\x00";
+        assert!(decompress(stream, 8).is_err());
+        // no end of stream marker
+        assert!(decompress(b"\x16hello", 64).is_err());
+    }
+}
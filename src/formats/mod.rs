@@ -0,0 +1,46 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+pub mod android;
+pub mod compress;
+pub mod jffs2;
+mod lzo;
+pub mod squashfs;
+pub mod ubootenv;
+pub mod uefi;
+pub mod uimage;
+
+pub fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub fn le24(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 3)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]))
+}
+
+pub fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub fn le64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8).map(|b| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(b);
+        u64::from_le_bytes(bytes)
+    })
+}
+
+pub fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub fn align(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
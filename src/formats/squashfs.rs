@@ -0,0 +1,568 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{
+    compress::{self, Compression},
+    le16, le32, le64,
+};
+use crate::error::*;
+use std::collections::HashMap;
+
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+const SUPERBLOCK_SZ: usize = 96;
+const METADATA_SZ: usize = 8192;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const DATA_UNCOMPRESSED: u32 = 0x0100_0000;
+const FRAGMENT_ENTRY_SZ: usize = 16;
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+
+const INODE_DIR: u16 = 1;
+const INODE_FILE: u16 = 2;
+const INODE_EXT_DIR: u16 = 8;
+const INODE_EXT_FILE: u16 = 9;
+
+// a directory cannot be nested deeper than this (loop protection)
+const MAX_DEPTH: usize = 256;
+
+/// A regular file of the filesystem
+#[derive(Debug)]
+pub struct File {
+    path: String,
+    size: usize,
+    blocks_start: usize,
+    block_sizes: Vec<u32>,
+    fragment: Option<(u32, usize)>,
+}
+
+impl File {
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// A SquashFS (v4.0) filesystem found in the flash image
+#[derive(Debug)]
+pub struct SquashFs {
+    offset: usize,
+    size: usize,
+    block_size: usize,
+    compression: Compression,
+    fragment_table: Vec<(usize, u32)>,
+    files: Vec<File>,
+}
+
+// A decompressed metadata table, with the position of each of its blocks
+struct Metadata {
+    data: Vec<u8>,
+    // position of a block in the filesystem (relative to the table start)
+    // -> position in 'data'
+    blocks: HashMap<usize, usize>,
+}
+
+impl Metadata {
+    // read the metadata blocks from 'start' up to 'end' (filesystem offsets)
+    fn read(fs: &[u8], compression: Compression, start: usize, end: usize) -> Result<Self> {
+        let mut metadata = Metadata {
+            data: Vec::new(),
+            blocks: HashMap::new(),
+        };
+        // the tables are within the filesystem
+        if end > fs.len() {
+            return Err(Error::InvalidSquashfs("truncated metadata"));
+        }
+        let mut pos = start;
+        while pos + 2 <= end {
+            let header = le16(fs, pos).ok_or(Error::InvalidSquashfs("truncated metadata"))?;
+            let size = (header & !METADATA_UNCOMPRESSED) as usize;
+            let block = fs
+                .get(pos + 2..pos + 2 + size)
+                .ok_or(Error::InvalidSquashfs("truncated metadata"))?;
+            metadata.blocks.insert(pos - start, metadata.data.len());
+            if header & METADATA_UNCOMPRESSED != 0 {
+                metadata.data.extend_from_slice(block);
+            } else {
+                let unpacked = compress::decompress(compression, block, METADATA_SZ)?;
+                metadata.data.extend_from_slice(&unpacked);
+            }
+            pos += 2 + size;
+        }
+        Ok(metadata)
+    }
+
+    // position in 'data' of a metadata reference (block << 16 | offset)
+    fn position(&self, block: usize, offset: usize) -> Result<usize> {
+        self.blocks
+            .get(&block)
+            .map(|pos| pos + offset)
+            .filter(|&pos| pos < self.data.len())
+            .ok_or(Error::InvalidSquashfs("bad metadata reference"))
+    }
+}
+
+fn get<T>(value: Option<T>) -> Result<T> {
+    value.ok_or(Error::InvalidSquashfs("truncated metadata"))
+}
+
+// everything needed to walk the directory tree
+struct Walker<'a> {
+    inodes: &'a Metadata,
+    dirs: &'a Metadata,
+    block_size: usize,
+    files: Vec<File>,
+}
+
+impl Walker<'_> {
+    fn inode(&mut self, inode_ref: u64, path: String, depth: usize) -> Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(Error::InvalidSquashfs("directory loop"));
+        }
+        let pos = self
+            .inodes
+            .position((inode_ref >> 16) as usize, (inode_ref & 0xffff) as usize)?;
+        let inode = &self.inodes.data[pos..];
+        let inode_type = get(le16(inode, 0))?;
+
+        match inode_type {
+            INODE_DIR | INODE_EXT_DIR => {
+                let (block, offset, size) = if inode_type == INODE_DIR {
+                    (
+                        get(le32(inode, 16))?,
+                        get(le16(inode, 26))? as u32,
+                        get(le16(inode, 24))? as u32,
+                    )
+                } else {
+                    (
+                        get(le32(inode, 24))?,
+                        get(le16(inode, 34))? as u32,
+                        get(le32(inode, 20))?,
+                    )
+                };
+                self.directory(block as usize, offset as usize, size as usize, &path, depth)
+            }
+            INODE_FILE | INODE_EXT_FILE => {
+                let (blocks_start, fragment, frag_offset, size, list_start) =
+                    if inode_type == INODE_FILE {
+                        (
+                            get(le32(inode, 16))? as u64,
+                            get(le32(inode, 20))?,
+                            get(le32(inode, 24))?,
+                            get(le32(inode, 28))? as u64,
+                            32,
+                        )
+                    } else {
+                        (
+                            get(le64(inode, 16))?,
+                            get(le32(inode, 44))?,
+                            get(le32(inode, 48))?,
+                            get(le64(inode, 24))?,
+                            56,
+                        )
+                    };
+                let size = size as usize;
+                let n_blocks = if fragment == NO_FRAGMENT {
+                    size.div_ceil(self.block_size)
+                } else {
+                    size / self.block_size
+                };
+                let block_sizes = (0..n_blocks)
+                    .map(|n| get(le32(inode, list_start + 4 * n)))
+                    .collect::<Result<Vec<u32>>>()?;
+                self.files.push(File {
+                    path,
+                    size,
+                    blocks_start: blocks_start as usize,
+                    block_sizes,
+                    fragment: if fragment == NO_FRAGMENT {
+                        None
+                    } else {
+                        Some((fragment, frag_offset as usize))
+                    },
+                });
+                Ok(())
+            }
+            // symlinks, devices...
+            _ => Ok(()),
+        }
+    }
+
+    fn directory(
+        &mut self,
+        block: usize,
+        offset: usize,
+        size: usize,
+        path: &str,
+        depth: usize,
+    ) -> Result<()> {
+        let start = self.dirs.position(block, offset)?;
+        // the size accounts for the '.' and '..' entries, which are not stored
+        let end = std::cmp::min(start + size.saturating_sub(3), self.dirs.data.len());
+        let data = &self.dirs.data;
+        let mut pos = start;
+
+        while pos + 12 <= end {
+            let count = get(le32(data, pos))? as usize + 1;
+            let inode_block = get(le32(data, pos + 4))? as u64;
+            pos += 12;
+            for _ in 0..count {
+                let inode_offset = get(le16(data, pos))? as u64;
+                let name_size = get(le16(data, pos + 6))? as usize + 1;
+                let name = data
+                    .get(pos + 8..pos + 8 + name_size)
+                    .ok_or(Error::InvalidSquashfs("truncated directory"))?;
+                let name = String::from_utf8_lossy(name);
+                pos += 8 + name_size;
+
+                let child_path = format!("{}/{}", path, name);
+                self.inode(inode_block << 16 | inode_offset, child_path, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SquashFs {
+    /// Parse the filesystem starting at `offset` in the flash image
+    pub fn parse(data: &[u8], offset: usize) -> Result<Self> {
+        let sb = data
+            .get(offset..offset + SUPERBLOCK_SZ)
+            .ok_or(Error::InvalidSquashfs("truncated superblock"))?;
+        if &sb[0..4] != SQUASHFS_MAGIC {
+            return Err(Error::InvalidSquashfs("bad magic"));
+        }
+        let block_size = le32(sb, 12).unwrap() as usize;
+        let block_log = le16(sb, 22).unwrap();
+        let frag_count = le32(sb, 16).unwrap() as usize;
+        let major = le16(sb, 28).unwrap();
+        if major != 4 || block_size == 0 || 1usize.checked_shl(block_log as u32) != Some(block_size)
+        {
+            return Err(Error::InvalidSquashfs("unsupported version"));
+        }
+        let compression = match le16(sb, 20).unwrap() {
+            1 => Compression::Zlib,
+            2 => Compression::Lzma,
+            3 => Compression::Lzo,
+            4 => Compression::Xz,
+            6 => Compression::Zstd,
+            _ => return Err(Error::InvalidSquashfs("unsupported compression")),
+        };
+        let root_inode = le64(sb, 32).unwrap();
+        let bytes_used = le64(sb, 40).unwrap() as usize;
+        let inode_table = le64(sb, 64).unwrap() as usize;
+        let dir_table = le64(sb, 72).unwrap() as usize;
+        let frag_table = le64(sb, 80).unwrap() as usize;
+        let fs = match offset.checked_add(bytes_used) {
+            Some(end) if bytes_used >= SUPERBLOCK_SZ && end <= data.len() => &data[offset..end],
+            _ => return Err(Error::InvalidSquashfs("bad superblock")),
+        };
+        if inode_table >= dir_table || dir_table >= bytes_used {
+            return Err(Error::InvalidSquashfs("bad superblock"));
+        }
+
+        // the directory table is followed by the fragment, export, id or
+        // xattr tables: stop at the first of them
+        let dir_end = [48, 56, 80, 88]
+            .iter()
+            .map(|&n| le64(sb, n).unwrap() as usize)
+            .filter(|&table| table > dir_table)
+            .fold(bytes_used, std::cmp::min);
+        let inodes = Metadata::read(fs, compression, inode_table, dir_table)?;
+        let dirs = Metadata::read(fs, compression, dir_table, dir_end)?;
+
+        // the fragment table is made of metadata blocks, which are indexed
+        // by the list of their positions at 'frag_table'
+        let mut fragment_table = Vec::new();
+        if frag_count > 0 && frag_table < bytes_used {
+            let n_blocks = (frag_count * FRAGMENT_ENTRY_SZ).div_ceil(METADATA_SZ);
+            for n in 0..n_blocks {
+                let block = get(le64(fs, frag_table + 8 * n))? as usize;
+                let end = block
+                    .checked_add(2)
+                    .ok_or(Error::InvalidSquashfs("bad fragment table"))?;
+                let metadata = Metadata::read(fs, compression, block, end)?;
+                for entry in metadata.data.chunks_exact(FRAGMENT_ENTRY_SZ) {
+                    fragment_table
+                        .push((le64(entry, 0).unwrap() as usize, le32(entry, 8).unwrap()));
+                }
+            }
+        }
+
+        let mut walker = Walker {
+            inodes: &inodes,
+            dirs: &dirs,
+            block_size,
+            files: Vec::new(),
+        };
+        walker.inode(root_inode, String::new(), 0)?;
+        let files = walker.files;
+
+        Ok(SquashFs {
+            offset,
+            size: bytes_used,
+            block_size,
+            compression,
+            fragment_table,
+            files,
+        })
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+
+    // read a data block, or a fragment block
+    fn block(&self, data: &[u8], pos: usize, size: u32) -> Result<Vec<u8>> {
+        let on_disk = (size & !DATA_UNCOMPRESSED) as usize;
+        let block = self
+            .offset
+            .checked_add(pos)
+            .and_then(|start| data.get(start..start.checked_add(on_disk)?))
+            .ok_or(Error::InvalidSquashfs("truncated data block"))?;
+        if size & DATA_UNCOMPRESSED != 0 {
+            Ok(block.to_vec())
+        } else {
+            compress::decompress(self.compression, block, self.block_size)
+        }
+    }
+
+    /// Content of a file of this filesystem
+    pub fn read(&self, data: &[u8], file: &File) -> Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(file.size);
+        let mut pos = file.blocks_start;
+        for &size in file.block_sizes.iter() {
+            let remaining = file.size - content.len();
+            if size & !DATA_UNCOMPRESSED == 0 {
+                // sparse block
+                let len = std::cmp::min(self.block_size, remaining);
+                content.resize(content.len() + len, 0);
+            } else {
+                let block = self.block(data, pos, size)?;
+                let len = std::cmp::min(block.len(), remaining);
+                content.extend_from_slice(&block[..len]);
+                pos += (size & !DATA_UNCOMPRESSED) as usize;
+            }
+        }
+        if let Some((index, offset)) = file.fragment {
+            let &(start, size) = self
+                .fragment_table
+                .get(index as usize)
+                .ok_or(Error::InvalidSquashfs("bad fragment index"))?;
+            let fragment = self.block(data, start, size)?;
+            let tail = fragment
+                .get(offset..offset + file.size - content.len())
+                .ok_or(Error::InvalidSquashfs("bad fragment offset"))?;
+            content.extend_from_slice(tail);
+        }
+        Ok(content)
+    }
+}
+
+/// Find every SquashFS filesystem of the flash image
+pub fn scan(data: &[u8]) -> Vec<SquashFs> {
+    let mut found = Vec::new();
+    let mut offset = 0;
+
+    // filesystems are at least 4-bytes aligned
+    while offset + SUPERBLOCK_SZ <= data.len() {
+        if &data[offset..offset + 4] == SQUASHFS_MAGIC {
+            if let Ok(fs) = SquashFs::parse(data, offset) {
+                offset += fs.size().div_ceil(4) * 4;
+                found.push(fs);
+                continue;
+            }
+        }
+        offset += 4;
+    }
+
+    found
+}
+
+/// Find the files whose content is `binary`, among the filesystems found in
+/// the flash image `data`
+pub fn find<'a>(
+    filesystems: &'a [SquashFs],
+    data: &[u8],
+    binary: &[u8],
+) -> Vec<(&'a SquashFs, &'a File)> {
+    let mut found = Vec::new();
+    for fs in filesystems.iter() {
+        // only decompress the files of the right size
+        for file in fs.files().iter().filter(|f| f.size() == binary.len()) {
+            if let Ok(content) = fs.read(data, file) {
+                if content == binary {
+                    found.push((fs, file));
+                }
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an uncompressed metadata block
+    fn metadata(content: &[u8]) -> Vec<u8> {
+        let mut block = (METADATA_UNCOMPRESSED | content.len() as u16)
+            .to_le_bytes()
+            .to_vec();
+        block.extend_from_slice(content);
+        block
+    }
+
+    fn inode_header(inode_type: u16, number: u32) -> Vec<u8> {
+        let mut inode = Vec::new();
+        inode.extend_from_slice(&inode_type.to_le_bytes());
+        inode.extend_from_slice(&[0; 10]);
+        inode.extend_from_slice(&number.to_le_bytes());
+        inode
+    }
+
+    fn file_inode(number: u32, start: u32, fragment: u32, size: u32, blocks: &[u32]) -> Vec<u8> {
+        let mut inode = inode_header(INODE_FILE, number);
+        for field in [start, fragment, 0, size].iter().chain(blocks) {
+            inode.extend_from_slice(&field.to_le_bytes());
+        }
+        inode
+    }
+
+    fn dir_inode(number: u32, listing_offset: u16, listing_size: u16) -> Vec<u8> {
+        let mut inode = inode_header(INODE_DIR, number);
+        inode.extend_from_slice(&0u32.to_le_bytes());
+        inode.extend_from_slice(&2u32.to_le_bytes());
+        // the size accounts for the '.' and '..' entries
+        inode.extend_from_slice(&(listing_size + 3).to_le_bytes());
+        inode.extend_from_slice(&listing_offset.to_le_bytes());
+        inode.extend_from_slice(&0u32.to_le_bytes());
+        inode
+    }
+
+    // a directory listing of (inode offset, inode type, name) entries, all
+    // in the first block of the inode table
+    fn listing(entries: &[(u16, u16, &str)]) -> Vec<u8> {
+        let mut listing = Vec::new();
+        listing.extend_from_slice(&(entries.len() as u32 - 1).to_le_bytes());
+        listing.extend_from_slice(&[0; 8]);
+        for (offset, inode_type, name) in entries {
+            listing.extend_from_slice(&offset.to_le_bytes());
+            listing.extend_from_slice(&0u16.to_le_bytes());
+            listing.extend_from_slice(&inode_type.to_le_bytes());
+            listing.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+            listing.extend_from_slice(name.as_bytes());
+        }
+        listing
+    }
+
+    // a filesystem holding '/README', stored in a fragment, and
+    // '/etc/hostname', stored in a data block
+    fn filesystem() -> Vec<u8> {
+        let mut fs = vec![0u8; SUPERBLOCK_SZ];
+        let hostname_start = fs.len() as u32;
+        fs.extend_from_slice(b"imgseek\n");
+        let fragment_start = fs.len() as u64;
+        fs.extend_from_slice(b"hello\n");
+
+        let etc_listing = listing(&[(0, INODE_FILE, "hostname")]);
+        let root_listing = listing(&[(36, INODE_FILE, "README"), (68, INODE_DIR, "etc")]);
+        let mut inodes = file_inode(1, hostname_start, NO_FRAGMENT, 8, &[8 | DATA_UNCOMPRESSED]);
+        inodes.extend(file_inode(2, 0, 0, 6, &[]));
+        inodes.extend(dir_inode(3, 0, etc_listing.len() as u16));
+        let root_ref = inodes.len() as u64;
+        inodes.extend(dir_inode(
+            4,
+            etc_listing.len() as u16,
+            root_listing.len() as u16,
+        ));
+
+        let inode_table = fs.len() as u64;
+        fs.extend(metadata(&inodes));
+        let dir_table = fs.len() as u64;
+        fs.extend(metadata(&[etc_listing, root_listing].concat()));
+        let fragment_entries = fs.len() as u64;
+        let mut entry = fragment_start.to_le_bytes().to_vec();
+        entry.extend_from_slice(&(6 | DATA_UNCOMPRESSED).to_le_bytes());
+        entry.extend_from_slice(&0u32.to_le_bytes());
+        fs.extend(metadata(&entry));
+        let frag_table = fs.len() as u64;
+        fs.extend_from_slice(&fragment_entries.to_le_bytes());
+
+        let bytes_used = fs.len() as u64;
+        let sb = &mut fs[..SUPERBLOCK_SZ];
+        sb[0..4].copy_from_slice(SQUASHFS_MAGIC);
+        sb[4..8].copy_from_slice(&4u32.to_le_bytes());
+        sb[12..16].copy_from_slice(&4096u32.to_le_bytes());
+        sb[16..20].copy_from_slice(&1u32.to_le_bytes());
+        // zlib, with a block log matching the block size
+        sb[20..22].copy_from_slice(&1u16.to_le_bytes());
+        sb[22..24].copy_from_slice(&12u16.to_le_bytes());
+        sb[28..30].copy_from_slice(&4u16.to_le_bytes());
+        for (n, value) in [
+            (32, root_ref),
+            (40, bytes_used),
+            (48, u64::MAX),
+            (56, u64::MAX),
+            (64, inode_table),
+            (72, dir_table),
+            (80, frag_table),
+            (88, u64::MAX),
+        ] {
+            sb[n..n + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        fs
+    }
+
+    #[test]
+    fn parse() {
+        // the filesystem is found past the start of the flash image
+        let mut data = vec![0xff; 0x100];
+        data.extend(filesystem());
+        let found = scan(&data);
+        assert_eq!(found.len(), 1);
+        let fs = &found[0];
+        assert_eq!(fs.offset(), 0x100);
+        assert_eq!(fs.size(), data.len() - 0x100);
+
+        let files: Vec<(&str, usize)> = fs.files().iter().map(|f| (f.path(), f.size())).collect();
+        assert_eq!(files, [("/README", 6), ("/etc/hostname", 8)]);
+        assert_eq!(fs.read(&data, &fs.files()[0]).unwrap(), b"hello\n");
+        assert_eq!(fs.read(&data, &fs.files()[1]).unwrap(), b"imgseek\n");
+
+        let found = find(&found, &data, b"imgseek\n");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1.path(), "/etc/hostname");
+    }
+
+    #[test]
+    fn bad_superblock() {
+        let mut fs = filesystem();
+        // bigger than the flash image
+        let too_big = fs.len() as u64 + 1;
+        fs[40..48].copy_from_slice(&too_big.to_le_bytes());
+        assert!(SquashFs::parse(&fs, 0).is_err());
+        // would overflow once added to its offset
+        fs[40..48].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(SquashFs::parse(&fs, 0).is_err());
+        let mut data = vec![0xff; 0x100];
+        data.extend(fs);
+        assert!(SquashFs::parse(&data, 0x100).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::le32;
+
+// CONFIG_ENV_SIZE values in use
+const ENV_SIZES: [usize; 8] = [
+    0x1000, 0x2000, 0x4000, 0x8000, 0x10000, 0x20000, 0x40000, 0x80000,
+];
+// environments are at least aligned on the smallest NAND page
+const ENV_ALIGN: usize = 0x200;
+
+/// A U-Boot environment: a CRC32 of the data, a flag byte for the redundant
+/// environments (CONFIG_SYS_REDUNDAND_ENVIRONMENT), then NUL-separated
+/// `key=value` variables, ending with an empty one
+#[derive(Debug)]
+pub struct Environment {
+    offset: usize,
+    size: usize,
+    big_endian: bool,
+    // whether the CRC matches, in either endianness
+    crc_ok: bool,
+    // flag byte of the redundant environments
+    flag: Option<u8>,
+    vars: Vec<(String, String)>,
+}
+
+impl Environment {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    pub fn is_crc_ok(&self) -> bool {
+        self.crc_ok
+    }
+
+    pub fn flag(&self) -> Option<u8> {
+        self.flag
+    }
+
+    pub fn vars(&self) -> &[(String, String)] {
+        &self.vars
+    }
+
+    /// Parse the environment of `size` bytes at `offset`, checking its CRC
+    pub fn parse(data: &[u8], offset: usize, size: usize, redundant: bool) -> Option<Self> {
+        let env = data.get(offset..offset + size)?;
+        let hdr_sz = if redundant { 5 } else { 4 };
+        let body = &env[hdr_sz..];
+        // the name of the first variable is checked before any CRC
+        if !starts_with_var(body) {
+            return None;
+        }
+        let crc = crc32fast::hash(body);
+        let stored = le32(env, 0)?;
+        let big_endian = if stored == crc {
+            false
+        } else if stored.swap_bytes() == crc {
+            true
+        } else {
+            return None;
+        };
+
+        Some(Environment {
+            offset,
+            size,
+            big_endian,
+            crc_ok: true,
+            flag: redundant.then(|| env[4]),
+            vars: parse_vars(body),
+        })
+    }
+
+    /// Parse the environment at `offset` whose CRC does not match, as long as
+    /// it holds well-formed variables. Its size is unknown: it is taken as the
+    /// smallest of the common sizes holding them.
+    pub fn parse_bad_crc(data: &[u8], offset: usize) -> Option<Self> {
+        [false, true].iter().find_map(|&redundant| {
+            let hdr_sz = if redundant { 5 } else { 4 };
+            let body = data.get(offset + hdr_sz..)?;
+            // cheap check first: most of the image holds no environment
+            if !starts_with_var(body) {
+                return None;
+            }
+            // the variables end with an empty one, within the largest
+            // environment fitting in the image
+            let max_size = *ENV_SIZES
+                .iter()
+                .rev()
+                .find(|&&size| offset + size <= data.len())?;
+            let body = &body[..max_size - hdr_sz];
+            let end = body.windows(2).position(|w| w == [0, 0])?;
+            let all_vars = body[..end].split(|b| *b == 0).all(|var| {
+                starts_with_var(var)
+                    && var
+                        .iter()
+                        .all(|b| b.is_ascii_graphic() || b" \t\r\n".contains(b))
+            });
+            if !all_vars {
+                return None;
+            }
+            let size = *ENV_SIZES.iter().find(|&&size| hdr_sz + end + 2 <= size)?;
+            Some(Environment {
+                offset,
+                size,
+                big_endian: false,
+                crc_ok: false,
+                flag: redundant.then(|| data[offset + 4]),
+                vars: parse_vars(body),
+            })
+        })
+    }
+}
+
+// NUL-separated `key=value` variables, ending with an empty one
+fn parse_vars(body: &[u8]) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for var in body.split(|b| *b == 0) {
+        if var.is_empty() {
+            break;
+        }
+        let var = String::from_utf8_lossy(var);
+        let (key, value) = var.split_once('=').unwrap_or((&var, ""));
+        vars.push((key.to_string(), value.to_string()));
+    }
+    vars
+}
+
+// whether the data starts with the name of a variable, and its '='
+fn starts_with_var(data: &[u8]) -> bool {
+    let name_len = data
+        .iter()
+        .take_while(|b| b.is_ascii_alphanumeric() || b"_-.".contains(b))
+        .count();
+    name_len > 0 && data.get(name_len) == Some(&b'=')
+}
+
+/// Find the U-Boot environments of the flash image, in the single and
+/// redundant formats, and of the common sizes. The environments with a bad
+/// CRC are reported too, flagged as such.
+pub fn scan(data: &[u8]) -> Vec<Environment> {
+    let mut found = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let env = [false, true]
+            .iter()
+            .find_map(|&redundant| {
+                ENV_SIZES
+                    .iter()
+                    .find_map(|&size| Environment::parse(data, pos, size, redundant))
+            })
+            .or_else(|| Environment::parse_bad_crc(data, pos));
+        match env {
+            Some(env) => {
+                pos += env.size();
+                found.push(env);
+            }
+            None => pos += ENV_ALIGN,
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // environment of `size` bytes, with the flag byte if any
+    fn env(size: usize, flag: Option<u8>, vars: &[&str], big_endian: bool) -> Vec<u8> {
+        let mut body = Vec::new();
+        for var in vars {
+            body.extend_from_slice(var.as_bytes());
+            body.push(0);
+        }
+        let hdr_sz = if flag.is_some() { 5 } else { 4 };
+        body.resize(size - hdr_sz, 0);
+        let crc = crc32fast::hash(&body);
+        let mut env = if big_endian {
+            crc.to_be_bytes().to_vec()
+        } else {
+            crc.to_le_bytes().to_vec()
+        };
+        env.extend(flag);
+        env.extend(body);
+        env
+    }
+
+    #[test]
+    fn scan_image() {
+        let vars = ["bootcmd=run distro_bootcmd", "bootdelay=2"];
+        let mut data = vec![0xff; 0x1000];
+        data.extend(env(0x2000, None, &vars, false));
+        data.extend(env(0x4000, Some(1), &vars, true));
+        data.extend(vec![0xff; 0x1000]);
+
+        let found = scan(&data);
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].offset(), found[0].size()), (0x1000, 0x2000));
+        assert!(found[0].is_crc_ok() && !found[0].is_big_endian());
+        assert_eq!(found[0].flag(), None);
+        assert_eq!(
+            found[0].vars(),
+            [
+                ("bootcmd".to_string(), "run distro_bootcmd".to_string()),
+                ("bootdelay".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!((found[1].offset(), found[1].size()), (0x3000, 0x4000));
+        assert!(found[1].is_crc_ok() && found[1].is_big_endian());
+        assert_eq!(found[1].flag(), Some(1));
+        assert_eq!(found[1].vars().len(), 2);
+    }
+
+    #[test]
+    fn bad_crc() {
+        let vars = ["bootcmd=run distro_bootcmd", "bootdelay=2"];
+        let mut data = env(0x2000, Some(0), &vars, false);
+        // a variable changed without updating the CRC
+        data[5 + "bootcmd=run distro_bootcmd\0bootdelay=".len()] = b'5';
+        data.extend(vec![0xff; 0x8000]);
+
+        let found = scan(&data);
+        assert_eq!(found.len(), 1);
+        assert!(!found[0].is_crc_ok());
+        assert_eq!(found[0].flag(), Some(0));
+        assert_eq!(
+            found[0].vars()[1],
+            ("bootdelay".to_string(), "5".to_string())
+        );
+        // the size is the smallest one holding the variables
+        assert_eq!((found[0].offset(), found[0].size()), (0, 0x1000));
+
+        // text which is not a list of variables
+        let mut data = vec![0xff; 0x1000];
+        data[4..20].copy_from_slice(b"a=1\0not a var\0\0\0");
+        assert!(scan(&data).is_empty());
+    }
+
+    #[test]
+    fn blank_regions() {
+        for fill in [0, 0xff] {
+            let data = vec![fill; 0x100000];
+            assert!(scan(&data).is_empty());
+            assert!(Environment::parse_bad_crc(&data, 0).is_none());
+        }
+        // variables running to the end of the image
+        let mut data = vec![b'a'; 0x1000];
+        data[4..6].copy_from_slice(b"a=");
+        assert!(Environment::parse_bad_crc(&data, 0).is_none());
+    }
+}
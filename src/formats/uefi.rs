@@ -0,0 +1,639 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{align, le16, le24, le32, le64};
+use std::{fmt, io::Cursor};
+
+const FV_SIGNATURE: &[u8] = b"_FVH";
+const FV_SIGNATURE_OFFSET: usize = 0x28;
+const FV_HEADER_MIN_SZ: usize = 0x38;
+
+const FFS2_GUID: &str = "8c8ce578-8a3d-4f1c-9935-896185c32dd3";
+const FFS3_GUID: &str = "5473c07a-3dcb-4dca-bd6f-1e9689e7349a";
+const LZMA_GUID: &str = "ee4e5898-3914-4259-9d6e-dc7bd79403cf";
+
+const FFS_HEADER_SZ: usize = 0x18;
+const FFS_HEADER2_SZ: usize = 0x20;
+const FFS_ATTRIB_LARGE_FILE: u8 = 0x01;
+const FFS_TYPE_RAW: u8 = 0x01;
+const FFS_TYPE_PAD: u8 = 0xf0;
+
+const SECTION_COMPRESSION: u8 = 0x01;
+const SECTION_GUID_DEFINED: u8 = 0x02;
+const SECTION_USER_INTERFACE: u8 = 0x15;
+const SECTION_FIRMWARE_VOLUME_IMAGE: u8 = 0x17;
+const GUIDED_PROCESSING_REQUIRED: u16 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    fn read(data: &[u8], offset: usize) -> Option<Self> {
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(data.get(offset..offset + 16)?);
+        Some(Guid(guid))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+        for b in g[10..].iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Volume,
+    File(u8),
+    Section(u8),
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::Volume => write!(f, "FV"),
+            EntryKind::File(t) => match t {
+                0x01 => write!(f, "RAW"),
+                0x02 => write!(f, "FREEFORM"),
+                0x03 => write!(f, "SEC_CORE"),
+                0x04 => write!(f, "PEI_CORE"),
+                0x05 => write!(f, "DXE_CORE"),
+                0x06 => write!(f, "PEIM"),
+                0x07 => write!(f, "DRIVER"),
+                0x08 => write!(f, "COMBINED_PEIM_DRIVER"),
+                0x09 => write!(f, "APPLICATION"),
+                0x0a => write!(f, "MM"),
+                0x0b => write!(f, "FV_IMAGE"),
+                0x0c => write!(f, "COMBINED_MM_DXE"),
+                0x0d => write!(f, "MM_CORE"),
+                0x0e => write!(f, "MM_STANDALONE"),
+                0x0f => write!(f, "MM_CORE_STANDALONE"),
+                _ => write!(f, "FILE({:#04x})", t),
+            },
+            EntryKind::Section(t) => match t {
+                0x01 => write!(f, "COMPRESSION section"),
+                0x02 => write!(f, "GUID_DEFINED section"),
+                0x10 => write!(f, "PE32 section"),
+                0x11 => write!(f, "PIC section"),
+                0x12 => write!(f, "TE section"),
+                0x13 => write!(f, "DXE_DEPEX section"),
+                0x14 => write!(f, "VERSION section"),
+                0x15 => write!(f, "USER_INTERFACE section"),
+                0x16 => write!(f, "COMPATIBILITY16 section"),
+                0x17 => write!(f, "FIRMWARE_VOLUME_IMAGE section"),
+                0x18 => write!(f, "FREEFORM_SUBTYPE_GUID section"),
+                0x19 => write!(f, "RAW section"),
+                0x1b => write!(f, "PEI_DEPEX section"),
+                0x1c => write!(f, "MM_DEPEX section"),
+                _ => write!(f, "section({:#04x})", t),
+            },
+        }
+    }
+}
+
+/// A firmware volume, a FFS file or a section, found in the flash image
+#[derive(Debug)]
+pub struct Entry {
+    kind: EntryKind,
+    guid: Option<Guid>,
+    name: Option<String>,
+    // offset in the flash image, unknown when inside a compressed section
+    offset: Option<usize>,
+    content: Vec<u8>,
+    header_len: usize,
+    depth: usize,
+    parent: Option<usize>,
+}
+
+impl Entry {
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.content.len()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Short description, e.g. `DRIVER 'DxeCore' (d6a2cb7f-...)`
+    pub fn label(&self) -> String {
+        match (&self.name, &self.guid) {
+            (Some(name), Some(guid)) => format!("{} '{}' ({})", self.kind, name, guid),
+            (None, Some(guid)) => format!("{} {}", self.kind, guid),
+            (Some(name), None) => format!("{} '{}'", self.kind, name),
+            (None, None) => self.kind.to_string(),
+        }
+    }
+
+    // content without its header
+    fn body(&self) -> &[u8] {
+        &self.content[self.header_len..]
+    }
+}
+
+// Walks the firmware volumes, and flattens them into a list of entries.
+// Every entry records its parent, and the offset of 'buf[0]' in the flash
+// image ('base') is given along the way, when known.
+struct Parser {
+    entries: Vec<Entry>,
+}
+
+impl Parser {
+    fn push(&mut self, entry: Entry) -> usize {
+        self.entries.push(entry);
+        self.entries.len() - 1
+    }
+
+    // parse the volume at 'start', return its length if valid
+    fn volume(
+        &mut self,
+        buf: &[u8],
+        start: usize,
+        base: Option<usize>,
+        depth: usize,
+        parent: Option<usize>,
+    ) -> Option<usize> {
+        if buf.get(start + FV_SIGNATURE_OFFSET..start + FV_SIGNATURE_OFFSET + 4)? != FV_SIGNATURE {
+            return None;
+        }
+        let fv_len = le64(buf, start + 0x20)? as usize;
+        let header_len = le16(buf, start + 0x30)? as usize;
+        let ext_offset = le16(buf, start + 0x34)? as usize;
+        if header_len < FV_HEADER_MIN_SZ
+            || fv_len < header_len
+            || start.checked_add(fv_len)? > buf.len()
+        {
+            return None;
+        }
+        let fv = &buf[start..start + fv_len];
+        // the 16 bits words of the header sum up to 0
+        let checksum = (0..header_len)
+            .step_by(2)
+            .fold(0u16, |sum, n| sum.wrapping_add(le16(fv, n).unwrap_or(0)));
+        if checksum != 0 {
+            return None;
+        }
+
+        let fs_guid = Guid::read(fv, 0x10)?;
+        let (name_guid, files_start) = if ext_offset != 0 {
+            let ext_size = le32(fv, ext_offset + 0x10)? as usize;
+            (Guid::read(fv, ext_offset), align(ext_offset + ext_size, 8))
+        } else {
+            (None, align(header_len, 8))
+        };
+
+        let index = self.push(Entry {
+            kind: EntryKind::Volume,
+            guid: Some(name_guid.unwrap_or(fs_guid)),
+            name: None,
+            offset: base.map(|b| b + start),
+            content: fv.to_vec(),
+            header_len,
+            depth,
+            parent,
+        });
+
+        let fs = fs_guid.to_string();
+        if fs == FFS2_GUID || fs == FFS3_GUID {
+            let fv_base = base.map(|b| b + start);
+            self.files(fv, files_start, fv_base, depth + 1, fs == FFS3_GUID, index);
+        }
+
+        Some(fv_len)
+    }
+
+    fn files(
+        &mut self,
+        fv: &[u8],
+        start: usize,
+        base: Option<usize>,
+        depth: usize,
+        ffs3: bool,
+        parent: usize,
+    ) {
+        let mut pos = start;
+        loop {
+            pos = align(pos, 8);
+            let header = match fv.get(pos..pos + FFS_HEADER_SZ) {
+                Some(header) => header,
+                None => break,
+            };
+            // erased flash: no more files
+            if header.iter().all(|&b| b == 0xff) {
+                break;
+            }
+            let file_type = header[0x12];
+            let attributes = header[0x13];
+            let (size, header_len) = if ffs3 && attributes & FFS_ATTRIB_LARGE_FILE != 0 {
+                match le64(fv, pos + FFS_HEADER_SZ) {
+                    Some(size) => (size as usize, FFS_HEADER2_SZ),
+                    None => break,
+                }
+            } else {
+                (le24(header, 0x14).unwrap() as usize, FFS_HEADER_SZ)
+            };
+            // the size of the large files is a 64 bits value from the flash
+            let end = match pos.checked_add(size) {
+                Some(end) if size >= header_len && end <= fv.len() => end,
+                _ => break,
+            };
+
+            if file_type != FFS_TYPE_PAD {
+                let file = &fv[pos..end];
+                let index = self.push(Entry {
+                    kind: EntryKind::File(file_type),
+                    guid: Guid::read(header, 0),
+                    name: None,
+                    offset: base.map(|b| b + pos),
+                    content: file.to_vec(),
+                    header_len,
+                    depth,
+                    parent: Some(parent),
+                });
+                if file_type != FFS_TYPE_RAW {
+                    let file_base = base.map(|b| b + pos);
+                    self.entries[index].name =
+                        self.sections(file, header_len, file_base, depth + 1, index);
+                }
+            }
+
+            pos = end;
+        }
+    }
+
+    // parse the sections of 'buf' from 'start', return the user interface
+    // name found among them
+    fn sections(
+        &mut self,
+        buf: &[u8],
+        start: usize,
+        base: Option<usize>,
+        depth: usize,
+        parent: usize,
+    ) -> Option<String> {
+        let mut ui_name = None;
+        let mut pos = start;
+        loop {
+            pos = align(pos, 4);
+            let (size, header_len) = match le24(buf, pos) {
+                Some(0x00ff_ffff) => match le32(buf, pos + 4) {
+                    Some(size) => (size as usize, 8),
+                    None => break,
+                },
+                Some(size) => (size as usize, 4),
+                None => break,
+            };
+            let end = match pos.checked_add(size) {
+                Some(end) if size >= header_len && end <= buf.len() => end,
+                _ => break,
+            };
+            let section = &buf[pos..end];
+            let section_type = section[3];
+            let section_base = base.map(|b| b + pos);
+
+            let index = self.push(Entry {
+                kind: EntryKind::Section(section_type),
+                guid: None,
+                name: None,
+                offset: section_base,
+                content: section.to_vec(),
+                header_len,
+                depth,
+                parent: Some(parent),
+            });
+
+            let nested_name = match section_type {
+                SECTION_COMPRESSION => {
+                    let body = header_len + 5;
+                    match section.get(header_len + 4) {
+                        // not compressed
+                        Some(0) if body <= size => {
+                            self.entries[index].header_len = body;
+                            self.sections(section, body, section_base, depth + 1, index)
+                        }
+                        // EFI standard compression is not supported
+                        _ => None,
+                    }
+                }
+                SECTION_GUID_DEFINED => {
+                    let guid = Guid::read(section, header_len);
+                    let data_offset = le16(section, header_len + 16).unwrap_or(0) as usize;
+                    let attributes = le16(section, header_len + 18).unwrap_or(0);
+                    self.entries[index].guid = guid;
+                    if data_offset < header_len || data_offset > size {
+                        None
+                    } else {
+                        self.entries[index].header_len = data_offset;
+                        if guid.map(|g| g.to_string() == LZMA_GUID).unwrap_or(false) {
+                            let mut reader = Cursor::new(&section[data_offset..]);
+                            let mut unpacked = Vec::new();
+                            match lzma_rs::lzma_decompress(&mut reader, &mut unpacked) {
+                                Ok(()) => self.sections(&unpacked, 0, None, depth + 1, index),
+                                Err(_) => None,
+                            }
+                        } else if attributes & GUIDED_PROCESSING_REQUIRED == 0 {
+                            self.sections(section, data_offset, section_base, depth + 1, index)
+                        } else {
+                            None
+                        }
+                    }
+                }
+                SECTION_FIRMWARE_VOLUME_IMAGE => {
+                    self.volume(section, header_len, section_base, depth + 1, Some(index));
+                    None
+                }
+                SECTION_USER_INTERFACE => {
+                    // NUL-terminated UCS-2 string
+                    let name: Vec<u16> = section[header_len..]
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .take_while(|&c| c != 0)
+                        .collect();
+                    Some(String::from_utf16_lossy(&name))
+                }
+                _ => None,
+            };
+            if ui_name.is_none() {
+                ui_name = nested_name;
+            }
+
+            pos = end;
+        }
+        ui_name
+    }
+}
+
+/// Find every firmware volume of the flash image, and list their content
+pub fn scan(data: &[u8]) -> Vec<Entry> {
+    let mut parser = Parser {
+        entries: Vec::new(),
+    };
+    let mut pos = FV_SIGNATURE_OFFSET;
+
+    while let Some(found) = data.get(pos..).and_then(|d| {
+        d.windows(FV_SIGNATURE.len())
+            .position(|w| w == FV_SIGNATURE)
+    }) {
+        let start = pos + found - FV_SIGNATURE_OFFSET;
+        pos = match parser.volume(data, start, Some(0), 0, None) {
+            Some(fv_len) => start + fv_len + FV_SIGNATURE_OFFSET,
+            None => pos + found + FV_SIGNATURE.len(),
+        };
+    }
+
+    parser.entries
+}
+
+/// A match of a binary with the content of an entry
+#[derive(Debug)]
+pub struct Match<'a> {
+    pub entry: &'a Entry,
+    /// flash offset of the binary, when the entry is not compressed
+    pub offset: Option<usize>,
+    /// the binary matches the entry without its header
+    pub body_only: bool,
+}
+
+/// Find the entries whose content, with or without their header, is `binary`
+pub fn find<'a>(entries: &'a [Entry], binary: &[u8]) -> Vec<Match<'a>> {
+    let mut found = Vec::new();
+    for entry in entries.iter() {
+        if entry.content == binary {
+            found.push(Match {
+                entry,
+                offset: entry.offset,
+                body_only: false,
+            });
+        } else if !binary.is_empty() && entry.body() == binary {
+            found.push(Match {
+                entry,
+                offset: entry.offset.map(|o| o + entry.header_len),
+                body_only: true,
+            });
+        }
+    }
+    found
+}
+
+/// Describe where `entry` is, e.g. `PE32 section of DRIVER 'DxeCore' (...)`
+pub fn describe(entries: &[Entry], entry: &Entry) -> String {
+    let mut desc = entry.label();
+    let mut parent = entry.parent;
+    // sections are named after the file they belong to
+    if let EntryKind::Section(_) = entry.kind {
+        while let Some(index) = parent {
+            let p = &entries[index];
+            if let EntryKind::File(_) = p.kind {
+                desc = format!("{} of {}", desc, p.label());
+                break;
+            }
+            parent = p.parent;
+        }
+    }
+    desc
+}
+
+/// List the volumes and the files, as an indented tree
+pub fn summary(entries: &[Entry]) -> String {
+    let mut s = String::new();
+    for entry in entries
+        .iter()
+        .filter(|e| !matches!(e.kind(), EntryKind::Section(_)))
+    {
+        let range = match entry.offset() {
+            Some(offset) => format!("{:#010x} - {:#010x}", offset, offset + entry.size()),
+            None => format!("{:^25}", "(compressed)"),
+        };
+        // sections are not listed, so only their parents count in the indent
+        let mut indent = String::new();
+        let mut parent = entry.parent;
+        while let Some(index) = parent {
+            if !matches!(entries[index].kind(), EntryKind::Section(_)) {
+                indent.push_str("  ");
+            }
+            parent = entries[index].parent;
+        }
+        s.push_str(&format!("\t{}\t{}{}\n", range, indent, entry.label()));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRIVER_GUID: &str = "d6a2cb7f-6a18-4e2f-b43b-9920a733700a";
+    const RAW_GUID: &str = "1ba0062e-c779-4582-8566-336ae8f78f09";
+
+    fn guid(s: &str) -> Vec<u8> {
+        let hex: Vec<u8> = s
+            .replace('-', "")
+            .as_bytes()
+            .chunks(2)
+            .map(|c| u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16).unwrap())
+            .collect();
+        // the first three fields are little endian
+        let mut bytes: Vec<u8> = hex[0..4].iter().rev().copied().collect();
+        bytes.extend(hex[4..6].iter().rev());
+        bytes.extend(hex[6..8].iter().rev());
+        bytes.extend(&hex[8..]);
+        bytes
+    }
+
+    fn section(section_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut s = ((body.len() + 4) as u32).to_le_bytes()[..3].to_vec();
+        s.push(section_type);
+        s.extend_from_slice(body);
+        s
+    }
+
+    fn file(name: &str, file_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut f = guid(name);
+        f.extend([0, 0, file_type, 0]);
+        f.extend(&((body.len() + FFS_HEADER_SZ) as u32).to_le_bytes()[..3]);
+        f.push(0xf8);
+        f.extend_from_slice(body);
+        f
+    }
+
+    // firmware volume of the file system `fs`, holding `files`, padded with
+    // erased flash up to `fv_len`
+    fn volume(fs: &str, files: &[Vec<u8>], fv_len: usize) -> Vec<u8> {
+        let header_len = 0x48;
+        let mut fv = vec![0; 0x10];
+        fv.extend(guid(fs));
+        fv.extend((fv_len as u64).to_le_bytes());
+        fv.extend(FV_SIGNATURE);
+        fv.extend([0xff, 0xfe, 0x03, 0x00]);
+        fv.extend((header_len as u16).to_le_bytes());
+        // checksum, no extended header, revision 2
+        fv.extend([0, 0, 0, 0, 0, 2]);
+        // block map: a single entry, then its terminator
+        fv.extend(1u32.to_le_bytes());
+        fv.extend((fv_len as u32).to_le_bytes());
+        fv.extend([0; 8]);
+        let sum = (0..header_len)
+            .step_by(2)
+            .fold(0u16, |sum, n| sum.wrapping_add(le16(&fv, n).unwrap()));
+        fv[0x32..0x34].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+        for f in files {
+            fv.resize(align(fv.len(), 8), 0xff);
+            fv.extend(f);
+        }
+        fv.resize(fv_len, 0xff);
+        fv
+    }
+
+    fn ui(name: &str) -> Vec<u8> {
+        let mut body: Vec<u8> = name.encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+        body.extend([0, 0]);
+        section(SECTION_USER_INTERFACE, &body)
+    }
+
+    #[test]
+    fn volume_and_files() {
+        let pe32 = section(0x10, b"MZ driver code");
+        let mut driver = pe32.clone();
+        driver.resize(align(driver.len(), 4), 0);
+        driver.extend(ui("DxeCore"));
+        let files = [
+            file(DRIVER_GUID, 0x07, &driver),
+            file(RAW_GUID, FFS_TYPE_RAW, b"raw blob"),
+        ];
+        let mut data = vec![0xff; 0x1000];
+        data.extend(volume(FFS2_GUID, &files, 0x1000));
+
+        let entries = scan(&data);
+        let kinds: Vec<(EntryKind, Option<usize>, usize)> = entries
+            .iter()
+            .map(|e| (e.kind(), e.offset(), e.depth()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (EntryKind::Volume, Some(0x1000), 0),
+                (EntryKind::File(0x07), Some(0x1048), 1),
+                (EntryKind::Section(0x10), Some(0x1060), 2),
+                (EntryKind::Section(SECTION_USER_INTERFACE), Some(0x1074), 2),
+                (EntryKind::File(FFS_TYPE_RAW), Some(0x1088), 1),
+            ]
+        );
+        assert_eq!(entries[0].size(), 0x1000);
+        assert_eq!(
+            entries[1].label(),
+            format!("DRIVER 'DxeCore' ({})", DRIVER_GUID)
+        );
+
+        // a binary is the body of the PE32 section
+        let found = find(&entries, b"MZ driver code");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].offset, Some(0x1064));
+        assert!(found[0].body_only);
+        assert_eq!(
+            describe(&entries, found[0].entry),
+            format!("PE32 section of DRIVER 'DxeCore' ({})", DRIVER_GUID)
+        );
+        let found = find(&entries, &files[1]);
+        assert_eq!((found[0].offset, found[0].body_only), (Some(0x1088), false));
+    }
+
+    #[test]
+    fn bad_volumes() {
+        let files = [file(RAW_GUID, FFS_TYPE_RAW, b"raw blob")];
+        // the header checksum does not match
+        let mut fv = volume(FFS2_GUID, &files, 0x200);
+        fv[0x2c] ^= 1;
+        assert!(scan(&fv).is_empty());
+        // the volume goes past the end of the image
+        let fv = volume(FFS2_GUID, &files, 0x200);
+        assert!(scan(&fv[..0x100]).is_empty());
+    }
+
+    #[test]
+    fn bad_files() {
+        // a file going past the end of its volume ends the walk
+        let mut truncated = file(RAW_GUID, FFS_TYPE_RAW, b"raw blob");
+        truncated[0x14..0x17].copy_from_slice(&[0x00, 0x10, 0x00]);
+        let files = [file(DRIVER_GUID, FFS_TYPE_RAW, b"first"), truncated];
+        let entries = scan(&volume(FFS2_GUID, &files, 0x200));
+        assert_eq!(entries.len(), 2);
+
+        // large file of the FFS3, its 64 bits size overflowing the offsets
+        let mut large = guid(RAW_GUID);
+        large.extend([0, 0, FFS_TYPE_RAW, FFS_ATTRIB_LARGE_FILE, 0, 0, 0, 0xf8]);
+        large.extend(u64::MAX.to_le_bytes());
+        let files = [file(DRIVER_GUID, FFS_TYPE_RAW, b"first"), large];
+        let entries = scan(&volume(FFS3_GUID, &files, 0x200));
+        assert_eq!(entries.len(), 2);
+
+        // a section going past the end of its file
+        let mut sections = section(0x10, b"MZ");
+        sections.extend([0xff, 0xff, 0x00, 0x19]);
+        let files = [file(DRIVER_GUID, 0x07, &sections)];
+        let entries = scan(&volume(FFS2_GUID, &files, 0x200));
+        assert_eq!(entries.len(), 3);
+    }
+}
@@ -0,0 +1,445 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{align, be32};
+use crate::layout::fdt::{Fdt, Node, FDT_MAGIC};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+const UIMAGE_HDR_SZ: usize = 64;
+const UIMAGE_NAME_SZ: usize = 32;
+
+/// Check of a CRC or a hash of an image
+#[derive(Debug)]
+pub struct Check {
+    name: String,
+    // None for the unsupported algorithms
+    valid: Option<bool>,
+}
+
+impl Check {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn valid(&self) -> Option<bool> {
+        self.valid
+    }
+}
+
+/// An image held by a legacy uImage, or one of the images of a FIT
+#[derive(Debug)]
+pub struct SubImage {
+    name: String,
+    kind: String,
+    compression: String,
+    load: Option<u64>,
+    entry: Option<u64>,
+    size: usize,
+    checks: Vec<Check>,
+}
+
+impl SubImage {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn kind(&self) -> &str {
+        self.kind.as_str()
+    }
+
+    pub fn compression(&self) -> &str {
+        self.compression.as_str()
+    }
+
+    pub fn load(&self) -> Option<u64> {
+        self.load
+    }
+
+    pub fn entry(&self) -> Option<u64> {
+        self.entry
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+}
+
+/// A U-Boot image: a legacy uImage, or a FIT (Flattened Image Tree)
+#[derive(Debug)]
+pub struct UbootImage {
+    offset: usize,
+    size: usize,
+    is_fit: bool,
+    images: Vec<SubImage>,
+}
+
+impl UbootImage {
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn format(&self) -> &'static str {
+        if self.is_fit {
+            "FIT"
+        } else {
+            "uImage"
+        }
+    }
+
+    pub fn images(&self) -> &[SubImage] {
+        &self.images
+    }
+
+    /// Whether a CRC or a hash does not match (or the data is truncated)
+    pub fn is_corrupted(&self) -> bool {
+        self.images
+            .iter()
+            .flat_map(|i| i.checks.iter())
+            .any(|c| c.valid == Some(false))
+    }
+
+    /// Parse the U-Boot image at `offset`, if any
+    pub fn parse(data: &[u8], offset: usize) -> Option<Self> {
+        match be32(data, offset)? {
+            UIMAGE_MAGIC => parse_legacy(data, offset),
+            FDT_MAGIC => parse_fit(data, offset),
+            _ => None,
+        }
+    }
+}
+
+fn legacy_type(kind: u8) -> String {
+    match kind {
+        1 => "standalone".to_string(),
+        2 => "kernel".to_string(),
+        3 => "ramdisk".to_string(),
+        4 => "multi".to_string(),
+        5 => "firmware".to_string(),
+        6 => "script".to_string(),
+        7 => "filesystem".to_string(),
+        8 => "flat_dt".to_string(),
+        14 => "kernel_noload".to_string(),
+        _ => format!("type {}", kind),
+    }
+}
+
+fn legacy_compression(compression: u8) -> String {
+    match compression {
+        0 => "none".to_string(),
+        1 => "gzip".to_string(),
+        2 => "bzip2".to_string(),
+        3 => "lzma".to_string(),
+        4 => "lzo".to_string(),
+        5 => "lz4".to_string(),
+        6 => "zstd".to_string(),
+        _ => format!("compression {}", compression),
+    }
+}
+
+fn parse_legacy(data: &[u8], offset: usize) -> Option<UbootImage> {
+    let hdr = data.get(offset..offset + UIMAGE_HDR_SZ)?;
+    let field = |n| be32(hdr, n).unwrap();
+    let data_size = field(12) as usize;
+
+    // the header CRC is computed with its own field cleared
+    let mut cleared = hdr.to_vec();
+    cleared[4..8].fill(0);
+    let header_crc = crc32fast::hash(&cleared) == field(4);
+    let body = data.get(offset + UIMAGE_HDR_SZ..offset + UIMAGE_HDR_SZ + data_size);
+    let data_crc = body.is_some_and(|b| crc32fast::hash(b) == field(24));
+    // a random match of the magic number
+    if !header_crc && !data_crc {
+        return None;
+    }
+
+    let name = &hdr[32..32 + UIMAGE_NAME_SZ];
+    let name = name.split(|b| *b == 0).next().unwrap();
+    let image = SubImage {
+        name: String::from_utf8_lossy(name).to_string(),
+        kind: legacy_type(hdr[30]),
+        compression: legacy_compression(hdr[31]),
+        load: Some(field(16) as u64),
+        entry: Some(field(20) as u64),
+        size: data_size,
+        checks: vec![
+            Check {
+                name: "header crc32".to_string(),
+                valid: Some(header_crc),
+            },
+            Check {
+                name: "data crc32".to_string(),
+                valid: Some(data_crc),
+            },
+        ],
+    };
+    Some(UbootImage {
+        offset,
+        size: UIMAGE_HDR_SZ + data_size,
+        is_fit: false,
+        images: vec![image],
+    })
+}
+
+// an address property, on one or two cells
+fn prop_address(node: &Node, name: &str) -> Option<u64> {
+    let value = node.prop(name)?;
+    match value.len() {
+        4 => be32(value, 0).map(|v| v as u64),
+        8 => Some(((be32(value, 0)? as u64) << 32) | be32(value, 4)? as u64),
+        _ => None,
+    }
+}
+
+fn check_hash(algo: &str, expected: &[u8], data: &[u8]) -> Option<bool> {
+    let digest = match algo {
+        "crc32" => crc32fast::hash(data).to_be_bytes().to_vec(),
+        "sha1" => Sha1::digest(data).to_vec(),
+        "sha256" => Sha256::digest(data).to_vec(),
+        _ => return None,
+    };
+    Some(digest == expected)
+}
+
+fn parse_fit(data: &[u8], offset: usize) -> Option<UbootImage> {
+    let fdt = Fdt::parse(&data[offset..]).ok()?;
+    // a plain device tree blob is not a FIT
+    let images_node = fdt
+        .root()
+        .children()
+        .iter()
+        .find(|n| n.name() == "images")?;
+
+    let mut size = fdt.size();
+    let mut images = Vec::new();
+    for node in images_node.children() {
+        // the data may be stored after the tree ('mkimage -E')
+        let (image_data, data_size) = match node.prop("data") {
+            Some(d) => (Some(d), d.len()),
+            None => {
+                let data_size = node.prop_u32("data-size").unwrap_or(0) as usize;
+                let position = node.prop_u32("data-position");
+                let start = match (position, node.prop_u32("data-offset")) {
+                    (Some(position), _) => position as usize,
+                    (None, Some(data_offset)) => align(fdt.size(), 4) + data_offset as usize,
+                    // no data
+                    (None, None) => continue,
+                };
+                size = std::cmp::max(size, start + data_size);
+                let d = data.get(offset + start..offset + start + data_size);
+                (d, data_size)
+            }
+        };
+
+        let mut checks = Vec::new();
+        for hash in node
+            .children()
+            .iter()
+            .filter(|n| n.name().starts_with("hash"))
+        {
+            let (algo, value) = match (hash.prop_str("algo"), hash.prop("value")) {
+                (Some(algo), Some(value)) => (algo, value),
+                _ => continue,
+            };
+            checks.push(Check {
+                name: algo.to_string(),
+                valid: match image_data {
+                    Some(d) => check_hash(algo, value, d),
+                    // truncated
+                    None => Some(false),
+                },
+            });
+        }
+        images.push(SubImage {
+            name: node.name().to_string(),
+            kind: node.prop_str("type").unwrap_or("unknown").to_string(),
+            compression: node.prop_str("compression").unwrap_or("none").to_string(),
+            load: prop_address(node, "load"),
+            entry: prop_address(node, "entry"),
+            size: data_size,
+            checks,
+        });
+    }
+    Some(UbootImage {
+        offset,
+        size,
+        is_fit: true,
+        images,
+    })
+}
+
+/// Parse the U-Boot images starting at the `offsets` of the flash image, e.g.
+/// the start of the binaries and regions found
+pub fn scan<I: Iterator<Item = usize>>(data: &[u8], offsets: I) -> Vec<UbootImage> {
+    let mut offsets: Vec<usize> = offsets.collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    offsets
+        .into_iter()
+        .filter_map(|offset| UbootImage::parse(data, offset))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::fdt::tests::Builder;
+
+    const KERNEL: &[u8] = b"kernel image";
+
+    fn legacy(data: &[u8]) -> Vec<u8> {
+        let mut hdr = vec![0; UIMAGE_HDR_SZ];
+        hdr[0..4].copy_from_slice(&UIMAGE_MAGIC.to_be_bytes());
+        hdr[12..16].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        hdr[16..20].copy_from_slice(&0x8000u32.to_be_bytes());
+        hdr[20..24].copy_from_slice(&0x8040u32.to_be_bytes());
+        hdr[24..28].copy_from_slice(&crc32fast::hash(data).to_be_bytes());
+        hdr[30] = 2;
+        hdr[32..38].copy_from_slice(b"Linux\0");
+        let crc = crc32fast::hash(&hdr);
+        hdr[4..8].copy_from_slice(&crc.to_be_bytes());
+        hdr.extend_from_slice(data);
+        hdr
+    }
+
+    #[test]
+    fn legacy_image() {
+        let mut data = vec![0xff; 0x100];
+        data.extend(legacy(KERNEL));
+        let found: Vec<UbootImage> = scan(&data, [0, 0x100, 0x100].into_iter());
+        assert_eq!(found.len(), 1);
+        let image = &found[0];
+        assert_eq!((image.offset(), image.size()), (0x100, 64 + KERNEL.len()));
+        assert_eq!(image.format(), "uImage");
+        assert!(!image.is_corrupted());
+        let sub = &image.images()[0];
+        assert_eq!(
+            (sub.name(), sub.kind(), sub.compression()),
+            ("Linux", "kernel", "none")
+        );
+        assert_eq!((sub.load(), sub.entry()), (Some(0x8000), Some(0x8040)));
+
+        // a corrupted data is reported, but the header is still recognized
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let image = UbootImage::parse(&data, 0x100).unwrap();
+        assert!(image.is_corrupted());
+        let checks: Vec<(&str, Option<bool>)> = image.images()[0]
+            .checks()
+            .iter()
+            .map(|c| (c.name(), c.valid()))
+            .collect();
+        assert_eq!(
+            checks,
+            [("header crc32", Some(true)), ("data crc32", Some(false))]
+        );
+        // and the magic number alone is not enough
+        data[0x100 + 4] ^= 1;
+        assert!(UbootImage::parse(&data, 0x100).is_none());
+    }
+
+    // FIT holding the kernel, and a device tree stored after it
+    fn fit(crc: u32) -> Vec<u8> {
+        let mut builder = Builder::default();
+        builder
+            .begin("")
+            .begin("images")
+            .begin("kernel")
+            .prop("data", KERNEL)
+            .prop("type", b"kernel\0")
+            .prop("compression", b"gzip\0")
+            .cells("load", &[0x8000])
+            .cells("entry", &[0, 0x8000])
+            .begin("hash-1")
+            .prop("algo", b"crc32\0")
+            .cells("value", &[crc])
+            .end()
+            .begin("hash-2")
+            .prop("algo", b"sha256\0")
+            .prop("value", &Sha256::digest(KERNEL))
+            .end()
+            .begin("hash-3")
+            .prop("algo", b"md5\0")
+            .prop("value", &[0; 16])
+            .end()
+            .end()
+            .begin("fdt")
+            .prop("type", b"flat_dt\0")
+            .cells("data-offset", &[0])
+            .cells("data-size", &[8])
+            .begin("hash-1")
+            .prop("algo", b"sha1\0")
+            .prop("value", &Sha1::digest(b"dtb blob"))
+            .end()
+            .end()
+            .end()
+            .end();
+        let mut blob = builder.finish();
+        blob.resize(align(blob.len(), 4), 0);
+        blob.extend(b"dtb blob");
+        blob
+    }
+
+    #[test]
+    fn fit_image() {
+        let data = fit(crc32fast::hash(KERNEL));
+        let image = UbootImage::parse(&data, 0).unwrap();
+        assert_eq!((image.format(), image.size()), ("FIT", data.len()));
+        assert!(!image.is_corrupted());
+        let kinds: Vec<(&str, &str, &str)> = image
+            .images()
+            .iter()
+            .map(|i| (i.name(), i.kind(), i.compression()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [("kernel", "kernel", "gzip"), ("fdt", "flat_dt", "none")]
+        );
+        let kernel = &image.images()[0];
+        assert_eq!(
+            (kernel.load(), kernel.entry(), kernel.size()),
+            (Some(0x8000), Some(0x8000), KERNEL.len())
+        );
+        assert_eq!(image.images()[1].size(), 8);
+        let checks: Vec<(&str, Option<bool>)> = image
+            .images()
+            .iter()
+            .flat_map(|i| i.checks())
+            .map(|c| (c.name(), c.valid()))
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                ("crc32", Some(true)),
+                ("sha256", Some(true)),
+                ("md5", None),
+                ("sha1", Some(true))
+            ]
+        );
+
+        assert!(UbootImage::parse(&fit(0), 0).unwrap().is_corrupted());
+        // the external data is truncated
+        let data = fit(crc32fast::hash(KERNEL));
+        let image = UbootImage::parse(&data[..data.len() - 1], 0).unwrap();
+        assert!(image.is_corrupted());
+    }
+
+    #[test]
+    fn plain_dtb() {
+        let mut builder = Builder::default();
+        builder.begin("").begin("chosen").end().end();
+        assert!(UbootImage::parse(&builder.finish(), 0).is_none());
+    }
+}
@@ -0,0 +1,252 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use std::io::{Cursor, Read};
+
+const CPIO_NEWC_MAGIC: &[u8] = b"070701";
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_ODC_MAGIC: &[u8] = b"070707";
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const CPIO_NEWC_HDR_SZ: usize = 110;
+const CPIO_ODC_HDR_SZ: usize = 76;
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+
+const TAR_MAGIC_OFFSET: usize = 257;
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Archive {
+    Tar,
+    /// cpio archive, as used by SWUpdate `.swu` bundles
+    Cpio,
+    Zip,
+}
+
+/// Identify archives by their magic number
+pub fn detect(data: &[u8]) -> Option<Archive> {
+    if data.starts_with(CPIO_NEWC_MAGIC)
+        || data.starts_with(CPIO_CRC_MAGIC)
+        || data.starts_with(CPIO_ODC_MAGIC)
+    {
+        Some(Archive::Cpio)
+    } else if data.starts_with(ZIP_MAGIC) {
+        Some(Archive::Zip)
+    } else if data
+        .get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5)
+        .is_some_and(|m| m == b"ustar")
+    {
+        Some(Archive::Tar)
+    } else {
+        None
+    }
+}
+
+/// Extract the (non-empty) regular files of an archive, with their path
+pub fn members(archive: Archive, data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    match archive {
+        Archive::Tar => tar_members(data),
+        Archive::Cpio => cpio_members(data),
+        Archive::Zip => zip_members(data),
+    }
+}
+
+fn tar_members(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut members = Vec::new();
+    let mut archive = tar::Archive::new(data);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() || entry.size() == 0 {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        members.push((path, content));
+    }
+    Ok(members)
+}
+
+fn zip_members(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut members = Vec::new();
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(data)).map_err(|_| Error::InvalidArchive("bad zip"))?;
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|_| Error::InvalidArchive("unsupported zip member"))?;
+        if !file.is_file() || file.size() == 0 {
+            continue;
+        }
+        let path = file.name().to_string();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        members.push((path, content));
+    }
+    Ok(members)
+}
+
+// parse an hexadecimal ('newc' format) or octal ('odc' format) header field
+fn cpio_field(data: &[u8], offset: usize, len: usize, radix: u32) -> Result<usize> {
+    data.get(offset..offset + len)
+        .and_then(|f| std::str::from_utf8(f).ok())
+        .and_then(|f| usize::from_str_radix(f, radix).ok())
+        .ok_or(Error::InvalidArchive("bad cpio header"))
+}
+
+fn cpio_members(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut members = Vec::new();
+    let mut pos = 0;
+    loop {
+        let hdr = data
+            .get(pos..)
+            .ok_or(Error::InvalidArchive("truncated cpio"))?;
+        // the 'newc' format aligns the names and the contents on 4 bytes
+        let (mode, namesize, filesize, name_pos, align) = if hdr.starts_with(CPIO_ODC_MAGIC) {
+            (
+                cpio_field(hdr, 18, 6, 8)?,
+                cpio_field(hdr, 59, 6, 8)?,
+                cpio_field(hdr, 65, 11, 8)?,
+                pos + CPIO_ODC_HDR_SZ,
+                1,
+            )
+        } else if hdr.starts_with(CPIO_NEWC_MAGIC) || hdr.starts_with(CPIO_CRC_MAGIC) {
+            (
+                cpio_field(hdr, 14, 8, 16)?,
+                cpio_field(hdr, 94, 8, 16)?,
+                cpio_field(hdr, 54, 8, 16)?,
+                pos + CPIO_NEWC_HDR_SZ,
+                4,
+            )
+        } else {
+            return Err(Error::InvalidArchive("bad cpio magic"));
+        };
+        let name = data
+            .get(name_pos..name_pos + namesize)
+            .ok_or(Error::InvalidArchive("truncated cpio"))?;
+        // the name is NUL-terminated
+        let name = String::from_utf8_lossy(name.split(|b| *b == 0).next().unwrap()).to_string();
+        if name == CPIO_TRAILER {
+            break;
+        }
+        let data_pos = (name_pos + namesize).div_ceil(align) * align;
+        let content = data
+            .get(data_pos..data_pos + filesize)
+            .ok_or(Error::InvalidArchive("truncated cpio"))?;
+        if mode as u32 & S_IFMT == S_IFREG && filesize > 0 {
+            members.push((name, content.to_vec()));
+        }
+        pos = (data_pos + filesize).div_ceil(align) * align;
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const FILES: [(&str, &[u8]); 2] = [("fw/u-boot.bin", b"u-boot"), ("rootfs.img", b"rootfs!")];
+
+    fn tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in FILES {
+            let mut header = tar::Header::new_ustar();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, content).unwrap();
+        }
+        // an empty file
+        let mut header = tar::Header::new_ustar();
+        header.set_size(0);
+        builder.append_data(&mut header, "empty", &[][..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn zip() -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_directory("fw", zip::write::FileOptions::default())
+            .unwrap();
+        for (name, content) in FILES {
+            writer
+                .start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    // 'newc' cpio archive, with a directory
+    fn cpio() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut entry = |name: &str, mode: u32, content: &[u8]| {
+            let header = format!(
+                "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+                0,
+                mode,
+                0,
+                0,
+                1,
+                0,
+                content.len(),
+                0,
+                0,
+                0,
+                0,
+                name.len() + 1,
+                0
+            );
+            data.extend(header.as_bytes());
+            data.extend(name.as_bytes());
+            data.push(0);
+            data.resize(data.len().div_ceil(4) * 4, 0);
+            data.extend(content);
+            data.resize(data.len().div_ceil(4) * 4, 0);
+        };
+        entry("fw", 0o040755, b"");
+        for (name, content) in FILES {
+            entry(name, S_IFREG | 0o644, content);
+        }
+        entry(CPIO_TRAILER, 0, b"");
+        data
+    }
+
+    fn expected() -> Vec<(String, Vec<u8>)> {
+        FILES
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn archives() {
+        for (archive, data) in [
+            (Archive::Tar, tar()),
+            (Archive::Zip, zip()),
+            (Archive::Cpio, cpio()),
+        ] {
+            assert_eq!(detect(&data), Some(archive));
+            assert_eq!(
+                members(archive, &data).unwrap(),
+                expected(),
+                "{:?}",
+                archive
+            );
+        }
+        assert_eq!(detect(b"u-boot"), None);
+    }
+
+    #[test]
+    fn bad_cpio() {
+        let data = cpio();
+        assert!(members(Archive::Cpio, &data[..data.len() - 8]).is_err());
+        let mut data = cpio();
+        data[60] = b'x';
+        assert!(members(Archive::Cpio, &data).is_err());
+    }
+}
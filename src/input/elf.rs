@@ -0,0 +1,176 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::Range;
+use crate::error::*;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+const PT_LOAD: u32 = 1;
+
+/// Whether `data` is an ELF file
+pub fn detect(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+/// Extract the content of the loadable segments of an ELF file, at their
+/// physical (load) address
+pub fn parse(data: &[u8]) -> Result<Vec<Range>> {
+    let is_64 = *data.get(4).ok_or(Error::InvalidElf("truncated header"))? == ELFCLASS64;
+    let is_be = *data.get(5).ok_or(Error::InvalidElf("truncated header"))? == ELFDATA2MSB;
+    let read = |offset: usize, len: usize| -> Result<u64> {
+        let bytes = offset
+            .checked_add(len)
+            .and_then(|end| data.get(offset..end))
+            .ok_or(Error::InvalidElf("truncated file"))?;
+        let fold = |v: u64, b: &u8| (v << 8) | *b as u64;
+        Ok(if is_be {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    };
+
+    let (phoff, phentsize, phnum) = if is_64 {
+        (read(32, 8)?, read(54, 2)?, read(56, 2)?)
+    } else {
+        (read(28, 4)?, read(42, 2)?, read(44, 2)?)
+    };
+    let mut ranges = Vec::new();
+    for i in 0..phnum as usize {
+        let ph = (i * phentsize as usize)
+            .checked_add(phoff as usize)
+            .ok_or(Error::InvalidElf("bad program header offset"))?;
+        if read(ph, 4)? as u32 != PT_LOAD {
+            continue;
+        }
+        // p_offset, p_paddr and p_filesz
+        let (offset, paddr, filesz) = if is_64 {
+            (read(ph + 8, 8)?, read(ph + 24, 8)?, read(ph + 32, 8)?)
+        } else {
+            (read(ph + 4, 4)?, read(ph + 12, 4)?, read(ph + 16, 4)?)
+        };
+        // segments only allocated in memory (.bss) are not in the flash
+        if filesz == 0 {
+            continue;
+        }
+        let content = offset
+            .checked_add(filesz)
+            .and_then(|end| data.get(offset as usize..end as usize))
+            .ok_or(Error::InvalidElf("segment out of the file"))?;
+        ranges.push(Range::new(Some(paddr), content.to_vec()));
+    }
+    if ranges.is_empty() {
+        return Err(Error::InvalidElf("no loadable segment"));
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (type, file offset, physical address, size in the file)
+    type Segment = (u32, u64, u64, u64);
+
+    // an ELF file made of its header, its program headers, then the data
+    // of its segments
+    fn elf(is_64: bool, is_be: bool, segments: &[Segment], content: &[u8]) -> Vec<u8> {
+        let word = |v: u64, len: usize| -> Vec<u8> {
+            let bytes = v.to_be_bytes()[8 - len..].to_vec();
+            if is_be {
+                bytes
+            } else {
+                bytes.into_iter().rev().collect()
+            }
+        };
+        let (ehsize, phentsize) = if is_64 { (64, 56) } else { (52, 32) };
+        let mut data = ELF_MAGIC.to_vec();
+        data.push(if is_64 { ELFCLASS64 } else { 1 });
+        data.push(if is_be { ELFDATA2MSB } else { 1 });
+        data.resize(ehsize, 0);
+        let fields = if is_64 {
+            [(32, 8, ehsize), (54, 2, phentsize), (56, 2, segments.len())]
+        } else {
+            [(28, 4, ehsize), (42, 2, phentsize), (44, 2, segments.len())]
+        };
+        for (offset, len, value) in fields {
+            data[offset..offset + len].copy_from_slice(&word(value as u64, len));
+        }
+        for &(p_type, offset, paddr, filesz) in segments {
+            let mut ph = vec![0; phentsize];
+            let fields = if is_64 {
+                [
+                    (0, 4, p_type as u64),
+                    (8, 8, offset),
+                    (24, 8, paddr),
+                    (32, 8, filesz),
+                ]
+            } else {
+                [
+                    (0, 4, p_type as u64),
+                    (4, 4, offset),
+                    (12, 4, paddr),
+                    (16, 4, filesz),
+                ]
+            };
+            for (offset, len, value) in fields {
+                ph[offset..offset + len].copy_from_slice(&word(value, len));
+            }
+            data.extend(ph);
+        }
+        data.extend_from_slice(content);
+        data
+    }
+
+    fn check(is_64: bool, is_be: bool) {
+        let start = if is_64 { 64 + 4 * 56 } else { 52 + 4 * 32 };
+        let segments = [
+            (PT_LOAD, start, 0x0800_0000, 4),
+            // not loadable
+            (4, start + 4, 0, 2),
+            (PT_LOAD, start + 6, 0x2000_0000, 2),
+            // .bss
+            (PT_LOAD, 0, 0x2000_1000, 0),
+        ];
+        let data = elf(is_64, is_be, &segments, b"codeNOda");
+        assert!(detect(&data));
+        let ranges = parse(&data).unwrap();
+        let expected = [
+            Range::new(Some(0x0800_0000), b"code".to_vec()),
+            Range::new(Some(0x2000_0000), b"da".to_vec()),
+        ];
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn elf32() {
+        check(false, false);
+        check(false, true);
+    }
+
+    #[test]
+    fn elf64() {
+        check(true, false);
+        check(true, true);
+    }
+
+    #[test]
+    fn bad_segments() {
+        let start = 64 + 56;
+        // out of the file
+        let data = elf(true, false, &[(PT_LOAD, start, 0, 8)], b"code");
+        assert!(parse(&data).is_err());
+        // would overflow
+        let data = elf(true, false, &[(PT_LOAD, u64::MAX - 1, 0, 4)], b"code");
+        assert!(parse(&data).is_err());
+        // nothing to load
+        let data = elf(false, false, &[(4, 52 + 32, 0, 4)], b"code");
+        assert!(parse(&data).is_err());
+        assert!(parse(b"\x7fELF").is_err());
+    }
+}
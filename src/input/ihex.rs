@@ -0,0 +1,96 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{hex_bytes, Range};
+use crate::error::*;
+
+const DATA: u8 = 0x00;
+const EOF: u8 = 0x01;
+const EXT_SEGMENT_ADDR: u8 = 0x02;
+const EXT_LINEAR_ADDR: u8 = 0x04;
+
+/// Whether `data` looks like an Intel HEX file
+pub fn detect(data: &[u8]) -> bool {
+    data.starts_with(b":") && data.iter().take(64).all(|b| b.is_ascii())
+}
+
+/// Parse the data records of an Intel HEX file, each of them being a range
+pub fn parse(content: &str) -> Result<Vec<Range>> {
+    let mut ranges = Vec::new();
+    let mut base: u64 = 0;
+    for (i, line) in content.lines().enumerate() {
+        let line_nb = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(hex_bytes)
+            .ok_or(Error::InvalidRecord("Intel HEX", line_nb))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(Error::InvalidRecord("Intel HEX", line_nb));
+        }
+        // the checksum makes the sum of all bytes null
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(Error::InvalidRecord("Intel HEX", line_nb));
+        }
+        let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            DATA => ranges.push(Range::new(Some(base + address), payload.to_vec())),
+            EOF => break,
+            EXT_SEGMENT_ADDR | EXT_LINEAR_ADDR if payload.len() == 2 => {
+                let value = u16::from_be_bytes([payload[0], payload[1]]) as u64;
+                base = if bytes[3] == EXT_SEGMENT_ADDR {
+                    value << 4
+                } else {
+                    value << 16
+                };
+            }
+            // start addresses
+            _ => (),
+        }
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "\
+:020000040800F2
+:10000000000102030405060708090A0B0C0D0E0F78
+:0400100010111213A6
+:02010000DEAD72
+:00000001FF
+";
+
+    #[test]
+    fn parse_records() {
+        assert!(detect(HEX.as_bytes()));
+        let ranges = parse(HEX).unwrap();
+        let expected = [
+            Range::new(Some(0x0800_0000), (0..16).collect()),
+            Range::new(Some(0x0800_0010), (16..20).collect()),
+            Range::new(Some(0x0800_0100), vec![0xde, 0xad]),
+        ];
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let hex = HEX.replace(":0400100010111213A6", ":0400100010111213A7");
+        assert!(matches!(
+            parse(&hex),
+            Err(Error::InvalidRecord("Intel HEX", 3))
+        ));
+        // the byte count does not match the record
+        let hex = HEX.replace(":0400100010111213A6", ":0500100010111213A5");
+        assert!(parse(&hex).is_err());
+    }
+}
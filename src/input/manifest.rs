@@ -0,0 +1,125 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use std::path::Path;
+
+/// A known artefact: only its name, size and SHA-256 digest are known
+#[derive(Debug)]
+pub struct Artefact {
+    name: String,
+    size: usize,
+    sha256: [u8; 32],
+}
+
+impl Artefact {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn sha256(&self) -> &[u8; 32] {
+        &self.sha256
+    }
+}
+
+fn parse_sha256(s: &str) -> Option<[u8; 32]> {
+    let bytes = super::hex_bytes(s)?;
+    bytes.try_into().ok()
+}
+
+/// Parse a manifest: one `sha256 size name` line per artefact, the size being
+/// either in decimal or in hexadecimal
+pub fn parse(content: &str) -> Result<Vec<Artefact>> {
+    let mut artefacts = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || Error::InvalidManifest(n + 1, line.to_string());
+        // the fields may be separated by several spaces, as written by the
+        // sha256sum-like tools, and the name is the rest of the line
+        let (sha256, rest) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let (size, name) = rest
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .ok_or_else(invalid)?;
+        let sha256 = parse_sha256(sha256).ok_or_else(invalid)?;
+        let size = super::parse_size(size).ok_or_else(invalid)?;
+        let name = name.trim();
+        if size == 0 {
+            return Err(invalid());
+        }
+        artefacts.push(Artefact {
+            name: name.to_string(),
+            size,
+            sha256,
+        });
+    }
+    Ok(artefacts)
+}
+
+/// Read the artefacts of a manifest file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Artefact>> {
+    let content = super::read(path)?;
+    parse(&String::from_utf8_lossy(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn parse_manifest() {
+        let content = format!(
+            "# release artefacts
+{sha}  5  u-boot.bin
+{sha}\t0x100\tsigned kernel.itb   # with a space
+{sha} 12 rootfs.ext4
+",
+            sha = SHA256
+        );
+        let artefacts = parse(&content).unwrap();
+        let found: Vec<(&str, usize)> = artefacts.iter().map(|a| (a.name(), a.size())).collect();
+        assert_eq!(
+            found,
+            [
+                ("u-boot.bin", 5),
+                ("signed kernel.itb", 0x100),
+                ("rootfs.ext4", 12)
+            ]
+        );
+        assert_eq!(artefacts[0].sha256()[..4], [0x2c, 0xf2, 0x4d, 0xba]);
+    }
+
+    #[test]
+    fn bad_lines() {
+        let lines = [
+            // no name
+            format!("{} 5", SHA256),
+            // bad size
+            format!("{} 0x 5 u-boot.bin", SHA256),
+            format!("{} 0 u-boot.bin", SHA256),
+            // bad digest
+            format!("{} 5 u-boot.bin", &SHA256[2..]),
+            format!("{}zz 5 u-boot.bin", &SHA256[2..]),
+        ];
+        for line in lines.iter() {
+            let content = format!("{} 5 ok.bin\n{}", SHA256, line);
+            assert!(
+                matches!(parse(&content), Err(Error::InvalidManifest(2, _))),
+                "{}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+mod archive;
+mod elf;
+mod ihex;
+pub mod manifest;
+pub mod paths;
+mod srec;
+
+use crate::error::*;
+use crate::formats::{android, compress};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+/// Read a file, decompressing it on the fly if it is a gzip, xz or zstd
+/// compressed file
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match compress::detect(reader.fill_buf()?) {
+        Some(compression) => compress::decompress_stream(compression, reader),
+        None => {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+}
+
+/// Parse a size or an offset given either in decimal or in hexadecimal ('0x'
+/// prefix)
+pub fn parse_size(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// A contiguous part of a binary to search. Its load address is known when
+/// the file format carries it (Intel HEX, S-record, ELF).
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Range {
+    address: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl Range {
+    pub fn new(address: Option<u64>, data: Vec<u8>) -> Self {
+        Range { address, data }
+    }
+
+    pub fn address(&self) -> Option<u64> {
+        self.address
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Name of the range of the binary `name`: the ranges extracted from a
+    /// file carrying their load address get it appended
+    pub fn name(&self, name: &str) -> String {
+        match self.address {
+            Some(address) => format!("{}@{:#x}", name, address),
+            None => name.to_string(),
+        }
+    }
+}
+
+// decode the hexadecimal digits of a text record
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// merge the records which follow each other in memory
+fn merge(mut ranges: Vec<Range>) -> Vec<Range> {
+    ranges.sort_by_key(|r| r.address);
+    let mut merged: Vec<Range> = Vec::new();
+    for range in ranges {
+        match (merged.last_mut(), range.address) {
+            (Some(last), Some(address))
+                if last.address.map(|a| a + last.len() as u64) == Some(address) =>
+            {
+                last.data.extend_from_slice(&range.data);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// turn the content of a binary into the raw data expected in the flash image
+fn to_ranges(data: Vec<u8>) -> Result<Vec<Range>> {
+    let ranges = if android::is_sparse(&data) {
+        vec![Range::new(None, android::expand_sparse(&data)?)]
+    } else if elf::detect(&data) {
+        elf::parse(&data)?
+    } else if ihex::detect(&data) {
+        ihex::parse(&String::from_utf8_lossy(&data))?
+    } else if srec::detect(&data) {
+        srec::parse(&String::from_utf8_lossy(&data))?
+    } else {
+        vec![Range::new(None, data)]
+    };
+    Ok(merge(ranges))
+}
+
+/// Read a binary to search, and turn it into the raw data expected in the
+/// flash image: compressed files are decompressed, Android sparse images are
+/// expanded, and the content of Intel HEX, S-record and ELF files is
+/// extracted, as one range per contiguous block of memory.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Range>> {
+    to_ranges(read(path)?)
+}
+
+/// A binary to search: a file, or a member of an archive
+#[derive(Debug)]
+pub struct Binary {
+    name: String,
+    // other names of the same content
+    aliases: Vec<String>,
+    ranges: Vec<Range>,
+}
+
+impl Binary {
+    fn new(name: String, ranges: Vec<Range>) -> Self {
+        Binary {
+            name,
+            aliases: Vec::new(),
+            ranges,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// The names of the binaries with the same content, see [`dedup`]
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    pub fn ranges(&self) -> &[Range] {
+        &self.ranges
+    }
+}
+
+// The binaries to search for the content of a file: Intel HEX, S-record and
+// ELF files may be stored as they are (e.g. in a filesystem), so the file
+// itself is searched as well as the ranges of memory it describes.
+fn candidates(name: String, data: Vec<u8>) -> Result<Vec<Binary>> {
+    if !(elf::detect(&data) || ihex::detect(&data) || srec::detect(&data)) {
+        return Ok(vec![Binary::new(name, to_ranges(data)?)]);
+    }
+    let ranges = to_ranges(data.clone())?;
+    Ok(vec![
+        Binary::new(name.clone(), vec![Range::new(None, data)]),
+        Binary::new(name, ranges),
+    ])
+}
+
+/// Load the binaries held by the file `path`: the file itself, and each member
+/// of an archive (tar, cpio, zip), named 'archive:member'
+pub fn load_binaries(path: &str) -> Result<Vec<Binary>> {
+    let data = read(path)?;
+    let archive = match archive::detect(&data) {
+        Some(archive) => archive,
+        None => return candidates(path.to_string(), data),
+    };
+    let members = archive::members(archive, &data)?;
+    // the archive may be stored as it is, e.g. an uncompressed initramfs
+    let mut binaries = vec![Binary::new(path.to_string(), vec![Range::new(None, data)])];
+    for (member, content) in members {
+        // members may be compressed on their own
+        let content = match compress::detect(&content) {
+            Some(compression) => compress::decompress_stream(compression, content.as_slice())?,
+            None => content,
+        };
+        let name = format!("{}:{}", path, member);
+        binaries.extend(candidates(name, content)?);
+    }
+    Ok(binaries)
+}
+
+/// Group the binaries with the same content, so that each content is only
+/// searched once. The first name of a group is kept, the others become its
+/// aliases.
+pub fn dedup(binaries: Vec<Binary>) -> Vec<Binary> {
+    let mut groups: Vec<Binary> = Vec::new();
+    // groups by hash of their content
+    let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+    for binary in binaries {
+        let mut hasher = DefaultHasher::new();
+        binary.ranges.hash(&mut hasher);
+        let candidates = by_hash.entry(hasher.finish()).or_default();
+        match candidates
+            .iter()
+            .find(|&&i| groups[i].ranges == binary.ranges)
+        {
+            Some(&i) => groups[i].aliases.push(binary.name),
+            None => {
+                candidates.push(groups.len());
+                groups.push(binary);
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_adjacent() {
+        let ranges = vec![
+            Range::new(Some(0x1004), vec![4, 5]),
+            Range::new(Some(0x1000), vec![0, 1, 2, 3]),
+            // a hole before this one
+            Range::new(Some(0x1007), vec![7]),
+            Range::new(Some(0x1008), vec![8]),
+        ];
+        let expected = [
+            Range::new(Some(0x1000), vec![0, 1, 2, 3, 4, 5]),
+            Range::new(Some(0x1007), vec![7, 8]),
+        ];
+        assert_eq!(merge(ranges), expected);
+    }
+
+    #[test]
+    fn records_and_file() {
+        let srec = b"S107100000010203E2\nS107100404050607CE\n".to_vec();
+        let binaries = candidates("fw.s19".to_string(), srec.clone()).unwrap();
+        assert_eq!(binaries.len(), 2);
+        // the file itself
+        assert_eq!(binaries[0].ranges(), [Range::new(None, srec)]);
+        // its records, merged
+        let expected = [Range::new(Some(0x1000), (0..8).collect())];
+        assert_eq!(binaries[1].ranges(), expected);
+        assert_eq!(binaries[1].ranges()[0].name("fw.s19"), "fw.s19@0x1000");
+
+        let binaries = candidates("fw.bin".to_string(), vec![0; 16]).unwrap();
+        assert_eq!(binaries.len(), 1);
+    }
+
+    #[test]
+    fn identical_binaries() {
+        let binaries = vec![
+            Binary::new("a.bin".to_string(), vec![Range::new(None, vec![1, 2])]),
+            Binary::new("b.bin".to_string(), vec![Range::new(None, vec![3])]),
+            Binary::new("copy/a.bin".to_string(), vec![Range::new(None, vec![1, 2])]),
+            // same content, at another address
+            Binary::new("a.hex".to_string(), vec![Range::new(Some(0), vec![1, 2])]),
+            Binary::new("old/a.bin".to_string(), vec![Range::new(None, vec![1, 2])]),
+        ];
+        let groups = dedup(binaries);
+        let names: Vec<(&str, &[String])> =
+            groups.iter().map(|b| (b.name(), b.aliases())).collect();
+        let aliases = ["copy/a.bin".to_string(), "old/a.bin".to_string()];
+        assert_eq!(
+            names,
+            [
+                ("a.bin", &aliases[..]),
+                ("b.bin", &[][..]),
+                ("a.hex", &[][..])
+            ]
+        );
+        assert_eq!(groups[0].ranges(), [Range::new(None, vec![1, 2])]);
+    }
+}
@@ -0,0 +1,112 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use glob::Pattern;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// Selection of the files found in directories, or matched by glob patterns.
+/// The binaries given explicitly are always kept.
+#[derive(Debug, Default)]
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    min_size: u64,
+}
+
+fn patterns<'a, I: Iterator<Item = &'a str>>(patterns: I) -> Result<Vec<Pattern>> {
+    patterns
+        .map(|p| Pattern::new(p).map_err(|_| Error::InvalidPattern(p.to_string())))
+        .collect()
+}
+
+impl Filter {
+    /// Keep the files whose name matches one of the `include` patterns (if
+    /// any), none of the `exclude` patterns, and whose size is at least
+    /// `min_size`
+    pub fn new<'a, I, E>(include: I, exclude: E, min_size: u64) -> Result<Self>
+    where
+        I: Iterator<Item = &'a str>,
+        E: Iterator<Item = &'a str>,
+    {
+        Ok(Filter {
+            include: patterns(include)?,
+            exclude: patterns(exclude)?,
+            min_size,
+        })
+    }
+
+    fn accept(&self, path: &Path) -> bool {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches(&name)))
+            && !self.exclude.iter().any(|p| p.matches(&name))
+            && size >= self.min_size
+    }
+}
+
+// whether the argument is a glob pattern rather than a path
+fn is_pattern(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+/// Expand the binaries given by the user: directories are walked
+/// recursively, glob patterns are matched, and every file is listed once,
+/// even if reached through several symbolic links.
+pub fn expand<'a, I>(args: I, filter: &Filter) -> Result<Vec<String>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut files: Vec<PathBuf> = Vec::new();
+    for arg in args {
+        let path = Path::new(arg);
+        if path.is_dir() {
+            let mut found = Vec::new();
+            for entry in WalkDir::new(path).follow_links(true) {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    // symbolic links to a parent directory
+                    Err(e) if e.loop_ancestor().is_some() => continue,
+                    Err(e) => return Err(Error::Io(e.into())),
+                };
+                if entry.file_type().is_file() && filter.accept(entry.path()) {
+                    found.push(entry.into_path());
+                }
+            }
+            found.sort();
+            files.extend(found);
+        } else if !path.exists() && is_pattern(arg) {
+            let matches = glob::glob(arg).map_err(|_| Error::InvalidPattern(arg.to_string()))?;
+            for entry in matches {
+                let entry = entry.map_err(|e| Error::Io(e.into()))?;
+                if entry.is_file() && filter.accept(&entry) {
+                    files.push(entry);
+                }
+            }
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    // resolve the symbolic links, to search each file once
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+    for file in files {
+        let canonical = fs::canonicalize(&file)?;
+        if seen.insert(canonical) {
+            unique.push(file.to_string_lossy().to_string());
+        }
+    }
+    Ok(unique)
+}
@@ -0,0 +1,103 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{hex_bytes, Range};
+use crate::error::*;
+
+/// Whether `data` looks like a Motorola S-record file
+pub fn detect(data: &[u8]) -> bool {
+    data.len() >= 2
+        && data[0] == b'S'
+        && data[1].is_ascii_digit()
+        && data.iter().take(64).all(|b| b.is_ascii())
+}
+
+/// Parse the data records (S1, S2 and S3) of an S-record file, each of them
+/// being a range
+pub fn parse(content: &str) -> Result<Vec<Range>> {
+    let mut ranges = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line_nb = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = Error::InvalidRecord("S-record", line_nb);
+        let (kind, hex) = match line.strip_prefix('S') {
+            Some(rest) if !rest.is_empty() => rest.split_at(1),
+            _ => return Err(err),
+        };
+        let bytes = match hex_bytes(hex) {
+            Some(bytes) => bytes,
+            None => return Err(err),
+        };
+        // the count covers the address, the data and the checksum
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(err);
+        }
+        let sum = bytes[..bytes.len() - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(err);
+        }
+        let addr_len = match kind {
+            "1" => 2,
+            "2" => 3,
+            "3" => 4,
+            // header, count and termination records
+            "0" | "5" | "6" | "7" | "8" | "9" => continue,
+            _ => return Err(err),
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(err);
+        }
+        let address = bytes[1..=addr_len]
+            .iter()
+            .fold(0u64, |addr, b| (addr << 8) | *b as u64);
+        let payload = &bytes[addr_len + 1..bytes.len() - 1];
+        ranges.push(Range::new(Some(address), payload.to_vec()));
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SREC: &str = "\
+S00600004844521B
+S10B10000001020304050607C8
+S20800100808090A0BB9
+S30708000000CAFE28
+S5030002FA
+S9031000EC
+";
+
+    #[test]
+    fn parse_records() {
+        assert!(detect(SREC.as_bytes()));
+        let ranges = parse(SREC).unwrap();
+        let expected = [
+            Range::new(Some(0x1000), (0..8).collect()),
+            Range::new(Some(0x1008), (8..12).collect()),
+            Range::new(Some(0x0800_0000), vec![0xca, 0xfe]),
+        ];
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let srec = SREC.replace("S30708000000CAFE28", "S30708000000CAFE29");
+        assert!(matches!(
+            parse(&srec),
+            Err(Error::InvalidRecord("S-record", 4))
+        ));
+        // unknown record type
+        let srec = SREC.replace("S5030002FA", "S4030002FA");
+        assert!(parse(&srec).is_err());
+    }
+}
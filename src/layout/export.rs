@@ -0,0 +1,279 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::Region;
+use crate::error::*;
+use std::{fmt::Write, path::Path, str::FromStr};
+
+/// Partition description formats that can be generated from a layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Linux kernel `mtdparts=` command line parameter
+    Mtdparts,
+    /// Device tree `fixed-partitions` node
+    Dts,
+    /// genimage configuration
+    Genimage,
+    /// flashrom layout file
+    Flashrom,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mtdparts" => Ok(Self::Mtdparts),
+            "dts" => Ok(Self::Dts),
+            "genimage" => Ok(Self::Genimage),
+            "flashrom" => Ok(Self::Flashrom),
+            _ => Err(Error::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+/// Parameters of the generated partition description
+#[derive(Debug)]
+pub struct ExportConfig<'a> {
+    /// erase block size, used to align every partition
+    pub erase_size: usize,
+    /// size of the whole flash
+    pub flash_size: usize,
+    /// name of the flash image file
+    pub image_name: &'a str,
+    /// MTD device identifier, for `mtdparts=`
+    pub mtd_id: &'a str,
+}
+
+// name of the partition holding a binary: its file name without extension
+fn partition_name(bin_name: &str) -> String {
+    Path::new(bin_name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| bin_name.to_string())
+}
+
+/// Turn the binaries found in the flash image into partitions, aligned on the
+/// erase block size, and sorted by offset.
+///
+/// The region names are kept as the binary name, they are converted into
+/// partition names by [`export`].
+pub fn align_regions(regions: &[Region], erase_size: usize, flash_size: usize) -> Vec<Region> {
+    let mut aligned: Vec<Region> = regions
+        .iter()
+        .map(|r| {
+            let start = r.offset() - r.offset() % erase_size;
+            let end = std::cmp::min(r.end().div_ceil(erase_size) * erase_size, flash_size);
+            Region::new(r.name().to_string(), start, end - start)
+        })
+        .collect();
+    aligned.sort_by_key(|r| r.offset());
+    aligned
+}
+
+/// List the pairs of partitions overlapping each other, once aligned
+pub fn overlaps(regions: &[Region]) -> Vec<(&Region, &Region)> {
+    let mut found = Vec::new();
+    for (i, a) in regions.iter().enumerate() {
+        for b in regions[i + 1..].iter() {
+            if a.offset() < b.end() && b.offset() < a.end() {
+                found.push((a, b));
+            }
+        }
+    }
+    found
+}
+
+/// Generate the partition description of `regions` (aligned with
+/// [`align_regions`]) in the requested format
+pub fn export(format: ExportFormat, regions: &[Region], config: &ExportConfig) -> String {
+    // a binary found several times gives several partitions: number them
+    let bases: Vec<String> = regions.iter().map(|r| partition_name(r.name())).collect();
+    let names: Vec<String> = bases
+        .iter()
+        .enumerate()
+        .map(
+            |(i, name)| match bases[..i].iter().filter(|n| *n == name).count() {
+                0 => name.to_string(),
+                count => format!("{}_{}", name, count),
+            },
+        )
+        .collect();
+    let parts = regions.iter().zip(names.iter());
+
+    let mut out = String::new();
+    match format {
+        ExportFormat::Mtdparts => {
+            let list: Vec<String> = parts
+                .map(|(r, name)| format!("{:#x}@{:#x}({})", r.size(), r.offset(), name))
+                .collect();
+            write!(out, "mtdparts={}:{}", config.mtd_id, list.join(",")).unwrap();
+        }
+        ExportFormat::Dts => {
+            out.push_str("partitions {\n");
+            out.push_str("\tcompatible = \"fixed-partitions\";\n");
+            out.push_str("\t#address-cells = <1>;\n");
+            out.push_str("\t#size-cells = <1>;\n");
+            for (r, name) in parts {
+                write!(
+                    out,
+                    "\n\tpartition@{:x} {{\n\t\tlabel = \"{}\";\n\t\treg = <{:#x} {:#x}>;\n\t}};\n",
+                    r.offset(),
+                    name,
+                    r.offset(),
+                    r.size()
+                )
+                .unwrap();
+            }
+            out.push_str("};");
+        }
+        ExportFormat::Genimage => {
+            let flash_type = "nor-imgseek";
+            write!(
+                out,
+                "flash {} {{\n\tpebsize = {}\n\tnumpebs = {}\n}}\n\n",
+                flash_type,
+                config.erase_size,
+                config.flash_size.div_ceil(config.erase_size)
+            )
+            .unwrap();
+            write!(
+                out,
+                "image {} {{\n\tflash {{\n\t}}\n\tflashtype = \"{}\"\n",
+                config.image_name, flash_type
+            )
+            .unwrap();
+            for (r, name) in parts {
+                let image = Path::new(r.name())
+                    .file_name()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_else(|| r.name().to_string());
+                write!(
+                    out,
+                    "\n\tpartition {} {{\n\t\timage = \"{}\"\n\t\toffset = {:#x}\n\t\tsize = {:#x}\n\t}}\n",
+                    name,
+                    image,
+                    r.offset(),
+                    r.size()
+                )
+                .unwrap();
+            }
+            out.push('}');
+        }
+        ExportFormat::Flashrom => {
+            let list: Vec<String> = parts
+                .map(|(r, name)| format!("{:08x}:{:08x} {}", r.offset(), r.end() - 1, name))
+                .collect();
+            out.push_str(&list.join("\n"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: ExportConfig = ExportConfig {
+        erase_size: 0x10000,
+        flash_size: 0x38000,
+        image_name: "flash.img",
+        mtd_id: "spi0.0",
+    };
+
+    // binaries found at unaligned offsets, one of them twice, and one ending
+    // past the end of the flash once aligned
+    fn regions() -> Vec<Region> {
+        let found = [
+            ("out/kernel.itb", 0x12000, 0x8000),
+            ("out/u-boot.bin", 0x100, 0x1000),
+            ("out/kernel.itb", 0x30000, 0x4000),
+        ];
+        let found: Vec<Region> = found
+            .iter()
+            .map(|&(name, offset, size)| Region::new(name.to_string(), offset, size))
+            .collect();
+        align_regions(&found, CONFIG.erase_size, CONFIG.flash_size)
+    }
+
+    #[test]
+    fn align() {
+        let regions = regions();
+        let aligned: Vec<(&str, usize, usize)> = regions
+            .iter()
+            .map(|r| (r.name(), r.offset(), r.size()))
+            .collect();
+        let expected = [
+            ("out/u-boot.bin", 0, 0x10000),
+            ("out/kernel.itb", 0x10000, 0x10000),
+            ("out/kernel.itb", 0x30000, 0x8000),
+        ];
+        assert_eq!(aligned, expected);
+    }
+
+    #[test]
+    fn aligned_overlaps() {
+        let found = [
+            Region::new("a".to_string(), 0x1000, 0x1000),
+            Region::new("b".to_string(), 0x8000, 0x1000),
+            Region::new("c".to_string(), 0x10000, 0x1000),
+        ];
+        let aligned = align_regions(&found, CONFIG.erase_size, CONFIG.flash_size);
+        let pairs: Vec<(&str, &str)> = overlaps(&aligned)
+            .iter()
+            .map(|(a, b)| (a.name(), b.name()))
+            .collect();
+        assert_eq!(pairs, [("a", "b")]);
+    }
+
+    #[test]
+    fn mtdparts() {
+        assert_eq!(
+            export(ExportFormat::Mtdparts, &regions(), &CONFIG),
+            "mtdparts=spi0.0:0x10000@0x0(u-boot),0x10000@0x10000(kernel),0x8000@0x30000(kernel_1)"
+        );
+    }
+
+    #[test]
+    fn dts() {
+        let dts = export(ExportFormat::Dts, &regions(), &CONFIG);
+        assert!(dts.starts_with("partitions {\n\tcompatible = \"fixed-partitions\";\n"));
+        assert!(dts.contains(
+            "\tpartition@10000 {\n\t\tlabel = \"kernel\";\n\t\treg = <0x10000 0x10000>;\n\t};\n"
+        ));
+        assert!(dts.contains(
+            "\tpartition@30000 {\n\t\tlabel = \"kernel_1\";\n\t\treg = <0x30000 0x8000>;\n\t};\n"
+        ));
+        assert!(dts.ends_with("};\n};"));
+    }
+
+    #[test]
+    fn genimage() {
+        let config = export(ExportFormat::Genimage, &regions(), &CONFIG);
+        // the last erase block is not complete
+        assert!(config.starts_with("flash nor-imgseek {\n\tpebsize = 65536\n\tnumpebs = 4\n}\n"));
+        assert!(config.contains("image flash.img {"));
+        assert!(config.contains(
+            "\tpartition u-boot {\n\t\timage = \"u-boot.bin\"\n\t\toffset = 0x0\n\t\tsize = 0x10000\n\t}\n"
+        ));
+        assert!(config.contains(
+            "\tpartition kernel_1 {\n\t\timage = \"kernel.itb\"\n\t\toffset = 0x30000\n\t\tsize = 0x8000\n\t}\n"
+        ));
+    }
+
+    #[test]
+    fn flashrom() {
+        assert_eq!(
+            export(ExportFormat::Flashrom, &regions(), &CONFIG),
+            "00000000:0000ffff u-boot\n00010000:0001ffff kernel\n00030000:00037fff kernel_1"
+        );
+        // the exported layout can be imported back
+        let layout = export(ExportFormat::Flashrom, &regions(), &CONFIG);
+        let imported = super::super::flashrom::parse(&layout).unwrap();
+        assert_eq!(imported.len(), 3);
+        assert_eq!(imported[2].end(), CONFIG.flash_size);
+    }
+}
@@ -0,0 +1,468 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{Layout, Region};
+use crate::error::*;
+use crate::formats::be32;
+use std::{fs, path::Path};
+
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SZ: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+// default values from the devicetree specification
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+const TRUNCATED: Error = Error::InvalidFdt("truncated blob");
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// read a NUL-terminated string
+fn cstr(data: &[u8], offset: usize) -> Result<&str> {
+    let tail = data
+        .get(offset..)
+        .ok_or(Error::InvalidFdt("string out of bounds"))?;
+    let len = tail
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(Error::InvalidFdt("unterminated string"))?;
+    std::str::from_utf8(&tail[..len]).map_err(|_| Error::InvalidFdt("non UTF-8 string"))
+}
+
+#[derive(Debug)]
+pub struct Node {
+    name: String,
+    props: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn children(&self) -> &[Node] {
+        &self.children
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&[u8]> {
+        self.props
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.prop(name).and_then(|v| be32(v, 0))
+    }
+
+    /// Value of a string property (first string of a string list)
+    pub fn prop_str(&self, name: &str) -> Option<&str> {
+        self.prop(name).and_then(|v| cstr(v, 0).ok())
+    }
+
+    /// Check the `compatible` string list against `compat`
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop("compatible")
+            .map(|v| v.split(|&c| c == 0).any(|s| s == compat.as_bytes()))
+            .unwrap_or(false)
+    }
+}
+
+/// A parsed Flattened Device Tree
+#[derive(Debug)]
+pub struct Fdt {
+    root: Node,
+    size: usize,
+}
+
+impl Fdt {
+    pub fn parse(blob: &[u8]) -> Result<Self> {
+        if blob.len() < FDT_HEADER_SZ || be32(blob, 0).ok_or(TRUNCATED)? != FDT_MAGIC {
+            return Err(Error::InvalidFdt("bad magic"));
+        }
+        let totalsize = be32(blob, 4).ok_or(TRUNCATED)? as usize;
+        let off_dt_struct = be32(blob, 8).ok_or(TRUNCATED)? as usize;
+        let off_dt_strings = be32(blob, 12).ok_or(TRUNCATED)? as usize;
+        let version = be32(blob, 20).ok_or(TRUNCATED)?;
+        if version < 16 {
+            return Err(Error::InvalidFdt("unsupported version"));
+        }
+        if totalsize < FDT_HEADER_SZ
+            || totalsize > blob.len()
+            || off_dt_struct >= totalsize
+            || off_dt_strings >= totalsize
+        {
+            return Err(Error::InvalidFdt("bad header"));
+        }
+        let blob = &blob[..totalsize];
+        let strings = &blob[off_dt_strings..];
+
+        // the root node must be the first token (NOPs apart)
+        let mut cursor = off_dt_struct;
+        loop {
+            match be32(blob, cursor).ok_or(TRUNCATED)? {
+                FDT_NOP => cursor += 4,
+                FDT_BEGIN_NODE => break,
+                _ => return Err(Error::InvalidFdt("missing root node")),
+            }
+        }
+        let root = Self::parse_node(blob, strings, &mut cursor)?;
+        if be32(blob, cursor).ok_or(TRUNCATED)? != FDT_END {
+            return Err(Error::InvalidFdt("missing end token"));
+        }
+
+        Ok(Fdt {
+            root,
+            size: totalsize,
+        })
+    }
+
+    // parse the node starting at 'cursor' (on its FDT_BEGIN_NODE token),
+    // and leave 'cursor' right after its FDT_END_NODE token
+    fn parse_node(blob: &[u8], strings: &[u8], cursor: &mut usize) -> Result<Node> {
+        *cursor += 4;
+        let name = cstr(blob, *cursor)?.to_string();
+        *cursor = align4(*cursor + name.len() + 1);
+
+        let mut node = Node {
+            name,
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+
+        loop {
+            match be32(blob, *cursor).ok_or(TRUNCATED)? {
+                FDT_BEGIN_NODE => {
+                    let child = Self::parse_node(blob, strings, cursor)?;
+                    node.children.push(child);
+                }
+                FDT_END_NODE => {
+                    *cursor += 4;
+                    return Ok(node);
+                }
+                FDT_PROP => {
+                    let len = be32(blob, *cursor + 4).ok_or(TRUNCATED)? as usize;
+                    let name_off = be32(blob, *cursor + 8).ok_or(TRUNCATED)? as usize;
+                    let value = blob
+                        .get(*cursor + 12..*cursor + 12 + len)
+                        .ok_or(Error::InvalidFdt("property out of bounds"))?;
+                    let prop_name = cstr(strings, name_off)?.to_string();
+                    node.props.push((prop_name, value.to_vec()));
+                    *cursor = align4(*cursor + 12 + len);
+                }
+                FDT_NOP => *cursor += 4,
+                _ => return Err(Error::InvalidFdt("unexpected token")),
+            }
+        }
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Size of the blob, as stated in its header
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Every `fixed-partitions` node of the tree, with their full path
+    pub fn fixed_partitions(&self) -> Vec<(String, &Node)> {
+        let mut found = Vec::new();
+        Self::walk(&self.root, "", &mut found);
+        found
+    }
+
+    fn walk<'a>(node: &'a Node, parent_path: &str, found: &mut Vec<(String, &'a Node)>) {
+        let path = if node.name().is_empty() {
+            String::from("/")
+        } else if parent_path == "/" {
+            format!("/{}", node.name())
+        } else {
+            format!("{}/{}", parent_path, node.name())
+        };
+        if node.is_compatible("fixed-partitions") {
+            found.push((path.clone(), node));
+        }
+        for child in node.children() {
+            Self::walk(child, &path, found);
+        }
+    }
+}
+
+// read a number stored on 'cells' 32 bits cells
+fn read_cells(data: &[u8], offset: usize, cells: u32) -> Result<usize> {
+    let mut value: u64 = 0;
+    for n in 0..cells as usize {
+        value = (value << 32) | be32(data, offset + 4 * n).ok_or(TRUNCATED)? as u64;
+    }
+    Ok(value as usize)
+}
+
+/// Convert a `fixed-partitions` node into a list of regions
+pub fn partitions_regions(node: &Node) -> Result<Vec<Region>> {
+    let address_cells = node
+        .prop_u32("#address-cells")
+        .unwrap_or(DEFAULT_ADDRESS_CELLS);
+    let size_cells = node.prop_u32("#size-cells").unwrap_or(DEFAULT_SIZE_CELLS);
+
+    let mut regions = Vec::new();
+    for child in node.children() {
+        let reg = match child.prop("reg") {
+            Some(reg) => reg,
+            // not a partition node
+            None => continue,
+        };
+        let offset = read_cells(reg, 0, address_cells)?;
+        let size = read_cells(reg, 4 * address_cells as usize, size_cells)?;
+        let name = child.prop_str("label").unwrap_or_else(|| child.name());
+        regions.push(Region::new(name.to_string(), offset, size));
+    }
+    regions.sort_by_key(|r| r.offset());
+    Ok(regions)
+}
+
+/// Find every valid device tree blob in the flash image, with their offset
+pub fn scan(data: &[u8]) -> Vec<(usize, Fdt)> {
+    let magic = FDT_MAGIC.to_be_bytes();
+    let mut found = Vec::new();
+    let mut offset = 0;
+
+    // DTBs are at least 4-bytes aligned
+    while offset + FDT_HEADER_SZ <= data.len() {
+        if data[offset..offset + 4] == magic {
+            if let Ok(fdt) = Fdt::parse(&data[offset..]) {
+                let size = fdt.size();
+                found.push((offset, fdt));
+                offset += align4(size);
+                continue;
+            }
+        }
+        offset += 4;
+    }
+
+    found
+}
+
+// pick the requested 'fixed-partitions' node, or the first one
+fn select_partitions(fdt: &Fdt, node_path: Option<&str>) -> Option<(String, Vec<Region>)> {
+    fdt.fixed_partitions()
+        .into_iter()
+        .find(|(path, _)| node_path.map(|p| p == path).unwrap_or(true))
+        .and_then(|(path, node)| partitions_regions(node).ok().map(|r| (path, r)))
+}
+
+/// Build the flash layout from a DTB file
+pub fn layout_from_file<P: AsRef<Path>>(dtb_path: P, node_path: Option<&str>) -> Result<Layout> {
+    let blob = fs::read(&dtb_path)?;
+    let fdt = Fdt::parse(&blob)?;
+
+    let (path, regions) = select_partitions(&fdt, node_path).ok_or(Error::NoPartitions)?;
+
+    Ok(Layout::new(
+        format!("device tree '{}' ({})", dtb_path.as_ref().display(), path),
+        regions,
+    ))
+}
+
+/// Build the flash layout from the first DTB, found inside the flash image,
+/// that describes fixed partitions
+pub fn layout_from_image(data: &[u8], node_path: Option<&str>) -> Result<Layout> {
+    scan(data)
+        .iter()
+        .find_map(|(offset, fdt)| {
+            select_partitions(fdt, node_path).map(|(path, regions)| {
+                Layout::new(
+                    format!("device tree at {:#010x} ({})", offset, path),
+                    regions,
+                )
+            })
+        })
+        .ok_or(Error::NoPartitions)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // a blob built token by token, without memory reservation
+    #[derive(Default)]
+    pub(crate) struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn token(&mut self, token: u32) {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            self.structs.resize(align4(self.structs.len()), 0);
+        }
+
+        pub(crate) fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        pub(crate) fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(self.strings.len() as u32);
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        pub(crate) fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        pub(crate) fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE);
+            self
+        }
+
+        pub(crate) fn finish(&mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let off_dt_struct = FDT_HEADER_SZ + 16;
+            let off_dt_strings = off_dt_struct + self.structs.len();
+            let totalsize = off_dt_strings + self.strings.len();
+            let header = [
+                FDT_MAGIC,
+                totalsize as u32,
+                off_dt_struct as u32,
+                off_dt_strings as u32,
+                FDT_HEADER_SZ as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|v| v.to_be_bytes()).collect();
+            blob.resize(off_dt_struct, 0);
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    fn dtb() -> Vec<u8> {
+        Builder::default()
+            .begin("")
+            .begin("flash@0")
+            .begin("partitions")
+            .prop("compatible", b"fixed-partitions\0")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[1])
+            .begin("partition@40000")
+            .cells("reg", &[0x40000, 0x10000])
+            .end()
+            .begin("partition@0")
+            .prop("label", b"u-boot\0")
+            .cells("reg", &[0, 0x40000])
+            .end()
+            .end()
+            .end()
+            // 2 address cells and 1 size cell by default
+            .begin("flash@1")
+            .begin("partitions")
+            .prop("compatible", b"foo\0fixed-partitions\0")
+            .begin("rootfs")
+            .cells("reg", &[0, 0x100000, 0x200000])
+            .end()
+            .end()
+            .end()
+            .end()
+            .finish()
+    }
+
+    fn summary(regions: &[Region]) -> Vec<(&str, usize, usize)> {
+        regions
+            .iter()
+            .map(|r| (r.name(), r.offset(), r.size()))
+            .collect()
+    }
+
+    #[test]
+    fn fixed_partitions() {
+        let blob = dtb();
+        let fdt = Fdt::parse(&blob).unwrap();
+        assert_eq!(fdt.size(), blob.len());
+        let nodes = fdt.fixed_partitions();
+        let paths: Vec<&str> = nodes.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, ["/flash@0/partitions", "/flash@1/partitions"]);
+
+        // sorted by offset, named by their label if any
+        let regions = partitions_regions(nodes[0].1).unwrap();
+        assert_eq!(
+            summary(&regions),
+            [
+                ("u-boot", 0, 0x40000),
+                ("partition@40000", 0x40000, 0x10000)
+            ]
+        );
+        let regions = partitions_regions(nodes[1].1).unwrap();
+        assert_eq!(summary(&regions), [("rootfs", 0x100000, 0x200000)]);
+
+        let (path, _) = select_partitions(&fdt, Some("/flash@1/partitions")).unwrap();
+        assert_eq!(path, "/flash@1/partitions");
+        assert!(select_partitions(&fdt, Some("/flash@2/partitions")).is_none());
+    }
+
+    #[test]
+    fn scan_image() {
+        let mut data = vec![0xff; 0x100];
+        // the magic number alone is not a blob
+        data[0x10..0x14].copy_from_slice(&FDT_MAGIC.to_be_bytes());
+        data.extend(dtb());
+        data.resize(data.len() + 0x100, 0xff);
+
+        let found = scan(&data);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, 0x100);
+        let layout = layout_from_image(&data, None).unwrap();
+        assert_eq!(layout.regions().len(), 2);
+    }
+
+    #[test]
+    fn bad_blobs() {
+        let blob = dtb();
+        assert!(Fdt::parse(&blob[..blob.len() - 1]).is_err());
+        let mut bad = blob.clone();
+        bad[0] = 0;
+        assert!(Fdt::parse(&bad).is_err());
+        // version 15
+        let mut bad = blob.clone();
+        bad[20..24].copy_from_slice(&15u32.to_be_bytes());
+        assert!(Fdt::parse(&bad).is_err());
+        // the first property (after the root, 'flash@0' and 'partitions'
+        // nodes) going past the blob
+        let mut bad = blob;
+        let prop = FDT_HEADER_SZ + 16 + 8 + 12 + 16;
+        bad[prop + 4..prop + 8].copy_from_slice(&0x1000u32.to_be_bytes());
+        assert!(matches!(
+            Fdt::parse(&bad),
+            Err(Error::InvalidFdt("property out of bounds"))
+        ));
+    }
+}
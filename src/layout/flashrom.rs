@@ -0,0 +1,91 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{Layout, Region};
+use crate::error::*;
+use std::{fs, path::Path};
+
+fn parse_hex(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// Parse a flashrom layout: one `start:end name` line per region, with both
+/// (inclusive) bounds in hexadecimal
+pub fn parse(content: &str) -> Result<Vec<Region>> {
+    let mut regions = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || Error::InvalidLayout(n + 1, line.to_string());
+        let (range, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+        let (start, end) = range.split_once(':').ok_or_else(invalid)?;
+        let start = parse_hex(start).ok_or_else(invalid)?;
+        let end = parse_hex(end).ok_or_else(invalid)?;
+        if end < start {
+            return Err(invalid());
+        }
+        regions.push(Region::new(name.trim().to_string(), start, end - start + 1));
+    }
+    regions.sort_by_key(|r| r.offset());
+    Ok(regions)
+}
+
+/// Build the flash layout from a flashrom layout file
+pub fn layout_from_file<P: AsRef<Path>>(layout_path: P) -> Result<Layout> {
+    let content = fs::read_to_string(&layout_path)?;
+    let regions = parse(&content)?;
+
+    Ok(Layout::new(
+        format!("flashrom layout '{}'", layout_path.as_ref().display()),
+        regions,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_layout() {
+        let content = "\
+# coreboot layout
+0x00001000:0x001fffff me
+00000000:00000fff fd
+00200000:003fffff   bios # the rest
+";
+        let regions: Vec<(String, usize, usize)> = parse(content)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.name().to_string(), r.offset(), r.size()))
+            .collect();
+        let expected = [
+            ("fd".to_string(), 0, 0x1000),
+            ("me".to_string(), 0x1000, 0x1ff000),
+            ("bios".to_string(), 0x200000, 0x200000),
+        ];
+        assert_eq!(regions, expected);
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert!(matches!(
+            parse("00000000:00000fff fd\n00001000-00001fff me"),
+            Err(Error::InvalidLayout(2, _))
+        ));
+        // no name
+        assert!(parse("00000000:00000fff").is_err());
+        // end before start
+        assert!(parse("00001000:00000fff fd").is_err());
+        assert!(parse("0000000g:00000fff fd").is_err());
+    }
+}
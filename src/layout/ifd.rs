@@ -0,0 +1,143 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use super::{Layout, Region};
+use crate::error::*;
+use crate::formats::le32;
+
+const IFD_SIGNATURE: u32 = 0x0ff0_a55a;
+// the signature is at 0x10 since the ICH9, and at 0x0 before
+const IFD_SIGNATURE_OFFSETS: [usize; 2] = [0x10, 0x0];
+// the descriptor region is the first 4 KiB of the flash
+const IFD_SIZE: usize = 0x1000;
+const IFD_MAX_REGIONS: usize = 16;
+
+// region names, as used by ifdtool
+const REGION_NAMES: [&str; IFD_MAX_REGIONS] = [
+    "fd", "bios", "me", "gbe", "pd", "res1", "bios2", "res2", "ec", "res3", "ie", "10gbe0",
+    "10gbe1", "res4", "res5", "ptt",
+];
+
+/// Offset of the Intel Flash Descriptor signature, if any
+pub fn find_signature(data: &[u8]) -> Option<usize> {
+    IFD_SIGNATURE_OFFSETS
+        .iter()
+        .copied()
+        .find(|&offset| le32(data, offset) == Some(IFD_SIGNATURE))
+}
+
+/// Parse the region table of the Intel Flash Descriptor
+pub fn parse(data: &[u8]) -> Result<Vec<Region>> {
+    let sig_offset = find_signature(data).ok_or(Error::NoIfd)?;
+    let flmap0 = le32(data, sig_offset + 4).ok_or(Error::NoIfd)?;
+    // Flash Region Base Address, in 16 bytes unit
+    let frba = (((flmap0 >> 16) & 0xff) << 4) as usize;
+
+    let mut regions = Vec::new();
+    for (n, name) in REGION_NAMES.iter().enumerate() {
+        let offset = frba + 4 * n;
+        // the region table cannot go past the descriptor itself
+        if offset + 4 > IFD_SIZE {
+            break;
+        }
+        let flreg = match le32(data, offset) {
+            Some(flreg) => flreg,
+            None => break,
+        };
+        // the descriptor region is the only one allowed to be at 0
+        if n > 0 && flreg == 0 {
+            continue;
+        }
+        let base = ((flreg & 0x7fff) << 12) as usize;
+        let limit = ((((flreg >> 16) & 0x7fff) << 12) | 0xfff) as usize;
+        // unused regions have their base above their limit, while garbage
+        // entries (on chipsets with less regions) end up past the flash
+        if base > limit || limit >= data.len() {
+            continue;
+        }
+        regions.push(Region::new(name.to_string(), base, limit - base + 1));
+    }
+
+    if regions.is_empty() {
+        return Err(Error::NoIfd);
+    }
+    regions.sort_by_key(|r| r.offset());
+    Ok(regions)
+}
+
+/// Build the flash layout from the Intel Flash Descriptor of the image
+pub fn layout_from_image(data: &[u8]) -> Result<Layout> {
+    let regions = parse(data)?;
+    Ok(Layout::new(String::from("Intel Flash Descriptor"), regions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLASH_SIZE: usize = 0x40_0000;
+    const FRBA: usize = 0x40;
+
+    fn flreg(base: usize, limit: usize) -> u32 {
+        (((limit >> 12) << 16) | (base >> 12)) as u32
+    }
+
+    // 4 MiB image with its descriptor signature at `sig_offset`, and the
+    // given region table
+    fn image(sig_offset: usize, flregs: &[u32]) -> Vec<u8> {
+        let mut data = vec![0xff; FLASH_SIZE];
+        data[sig_offset..sig_offset + 4].copy_from_slice(&IFD_SIGNATURE.to_le_bytes());
+        let flmap0 = ((FRBA >> 4) as u32) << 16;
+        data[sig_offset + 4..sig_offset + 8].copy_from_slice(&flmap0.to_le_bytes());
+        for (n, flreg) in flregs.iter().enumerate() {
+            data[FRBA + 4 * n..FRBA + 4 * n + 4].copy_from_slice(&flreg.to_le_bytes());
+        }
+        data
+    }
+
+    fn summary(regions: &[Region]) -> Vec<(&str, usize, usize)> {
+        regions
+            .iter()
+            .map(|r| (r.name(), r.offset(), r.size()))
+            .collect()
+    }
+
+    #[test]
+    fn regions() {
+        let flregs = [
+            flreg(0, 0xfff),
+            flreg(0x20_0000, 0x3f_ffff),
+            flreg(0x3000, 0x1f_ffff),
+            flreg(0x1000, 0x2fff),
+            // unused platform data region
+            flreg(0x7fff000, 0),
+            // garbage entry, past the end of the flash
+            flreg(0x40_0000, 0x7f_ffff),
+            // unused entry
+            0,
+        ];
+        let expected = [
+            ("fd", 0, 0x1000),
+            ("gbe", 0x1000, 0x2000),
+            ("me", 0x3000, 0x1f_d000),
+            ("bios", 0x20_0000, 0x20_0000),
+        ];
+        for sig_offset in IFD_SIGNATURE_OFFSETS {
+            let data = image(sig_offset, &flregs);
+            assert_eq!(find_signature(&data), Some(sig_offset));
+            assert_eq!(summary(&parse(&data).unwrap()), expected);
+        }
+    }
+
+    #[test]
+    fn no_descriptor() {
+        assert!(matches!(parse(&vec![0xff; FLASH_SIZE]), Err(Error::NoIfd)));
+        assert!(matches!(parse(&[]), Err(Error::NoIfd)));
+        // a signature, but only unused regions
+        let data = image(0x10, &[flreg(0x7fff000, 0); 4]);
+        assert!(matches!(parse(&data), Err(Error::NoIfd)));
+    }
+}
@@ -0,0 +1,147 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+pub mod export;
+pub mod fdt;
+pub mod flashrom;
+pub mod ifd;
+
+use colored::Colorize;
+use std::fmt;
+
+/// A named area of the flash image, as described by a partition table
+#[derive(Debug, Clone)]
+pub struct Region {
+    name: String,
+    offset: usize,
+    size: usize,
+}
+
+impl Region {
+    pub fn new(name: String, offset: usize, size: usize) -> Self {
+        Region { name, offset, size }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.size
+    }
+}
+
+/// List of regions describing the flash image, along with where it comes from
+#[derive(Debug)]
+pub struct Layout {
+    source: String,
+    regions: Vec<Region>,
+}
+
+impl Layout {
+    pub fn new(source: String, regions: Vec<Region>) -> Self {
+        Layout { source, regions }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|r| r.name() == name)
+    }
+
+    /// Regions the range `[offset, offset + size)` lies in, even partially
+    pub fn locate(&self, offset: usize, size: usize) -> Vec<&Region> {
+        self.regions
+            .iter()
+            .filter(|r| r.offset() < offset + size && offset < r.end())
+            .collect()
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", format!("➜ Layout from {}:", self.source).bold())?;
+        for region in self.regions.iter() {
+            writeln!(
+                f,
+                "\t{:#010x} - {:#010x} ({:#x})\t'{}'",
+                region.offset(),
+                region.end(),
+                region.size(),
+                region.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of the check of a binary against the region it is expected in
+#[derive(Debug)]
+pub enum Expectation {
+    /// found at the start of the region, and fits in it
+    Match,
+    /// found at the start of the region, but larger than it
+    Overflow(usize),
+    /// not found at the start of the region, maybe elsewhere
+    Misplaced(Vec<usize>),
+    /// the region does not exist in the layout
+    UnknownRegion,
+}
+
+impl Expectation {
+    /// Check whether a binary of size `bin_size`, found at `offsets` in the
+    /// flash image, is the content of `region`
+    pub fn check(region: Option<&Region>, bin_size: usize, offsets: &[usize]) -> Self {
+        match region {
+            None => Expectation::UnknownRegion,
+            Some(r) => {
+                if !offsets.contains(&r.offset()) {
+                    Expectation::Misplaced(offsets.to_vec())
+                } else if bin_size > r.size() {
+                    Expectation::Overflow(bin_size - r.size())
+                } else {
+                    Expectation::Match
+                }
+            }
+        }
+    }
+
+    pub fn is_match(&self) -> bool {
+        matches!(self, Self::Match)
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expectation::Match => write!(f, "{}", "OK".green()),
+            Expectation::Overflow(n) => {
+                write!(f, "{} ({:#x} bytes past the end)", "OVERFLOW".red(), n)
+            }
+            Expectation::Misplaced(offsets) if offsets.is_empty() => {
+                write!(f, "{}", "MISSING".red())
+            }
+            Expectation::Misplaced(offsets) => {
+                write!(f, "{} (found at", "MISPLACED".red())?;
+                for offset in offsets.iter() {
+                    write!(f, " {:#010x}", offset)?;
+                }
+                write!(f, ")")
+            }
+            Expectation::UnknownRegion => write!(f, "{}", "UNKNOWN REGION".red()),
+        }
+    }
+}
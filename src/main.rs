@@ -5,31 +5,184 @@
 //
 //
 
+mod assemble;
 mod cli;
+mod consensus;
 mod error;
+mod extract;
+mod formats;
+mod input;
+mod layout;
 mod puzzle;
+mod replace;
 mod seeker;
+mod verify;
 
+use anyhow::{anyhow, bail};
+use clap::ArgMatches;
 use colored::Colorize;
-use puzzle::{PuzzleDisplay, PuzzlePiece};
+use formats::{android, jffs2, squashfs, ubootenv, uefi, uimage};
+use input::{
+    manifest::{self, Artefact},
+    paths::{self, Filter},
+};
+use layout::{
+    export::{self, ExportConfig, ExportFormat},
+    fdt, flashrom, ifd, Expectation, Layout, Region,
+};
+use puzzle::{PieceKind, PuzzleDisplay, PuzzlePiece};
 use seeker::FlashImage;
 #[cfg(debug_assertions)]
 use std::time::Instant;
 use std::{
-    convert::TryInto,
+    collections::BTreeMap,
     fs, panic,
+    path::Path,
     sync::{Arc, Mutex},
     thread,
 };
 
-fn main() -> anyhow::Result<()> {
-    let matches = cli::build_cli().get_matches();
+// Parse a size given on the command line
+fn parse_size(s: &str) -> anyhow::Result<usize> {
+    input::parse_size(s).ok_or_else(|| anyhow!("invalid size '{}'", s))
+}
+
+// Get the flash layout from the first source provided by the user, if any.
+// Otherwise, x86 images describe their own layout in their flash descriptor.
+fn load_layout(matches: &ArgMatches, flash_image: &FlashImage) -> anyhow::Result<Option<Layout>> {
+    let node_path = matches.value_of("dtb_node");
+    let layout = if let Some(dtb) = matches.value_of("dtb") {
+        Some(fdt::layout_from_file(dtb, node_path)?)
+    } else if matches.is_present("dtb_scan") {
+        Some(fdt::layout_from_image(flash_image.data(), node_path)?)
+    } else if let Some(layout_file) = matches.value_of("flashrom_layout") {
+        Some(flashrom::layout_from_file(layout_file)?)
+    } else {
+        ifd::layout_from_image(flash_image.data()).ok()
+    };
+    Ok(layout)
+}
+
+// Tell whether a range sits at the offset of the flash image given by its
+// load address
+fn check_load_address(address: u64, load_base: u64, offset: usize) -> String {
+    match address.checked_sub(load_base) {
+        Some(expected) if expected == offset as u64 => {
+            format!(" (load address {:#x}: {})", address, "OK".green())
+        }
+        Some(expected) => format!(
+            " (load address {:#x}: {}, expected at {:#010x})",
+            address,
+            "MISPLACED".red(),
+            expected
+        ),
+        None => format!(
+            " (load address {:#x}: {}, below the load base)",
+            address,
+            "MISPLACED".red()
+        ),
+    }
+}
+
+// Check each 'region=binary' expectation against the flash layout
+fn check_expectations<'a, I>(
+    expectations: I,
+    layout: &Layout,
+    flash_image: &FlashImage,
+    bsize: usize,
+) -> anyhow::Result<bool>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut all_match = true;
+    println!("{}", "➜ Expected content of the flash layout:".bold());
+    for expect in expectations {
+        let (region_name, binary_name) = expect
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid expectation '{}', use 'region=binary'", expect))?;
+        let ranges = flash_image.seek_image(binary_name, bsize)?;
+        for (range, offsets) in ranges {
+            let result = Expectation::check(layout.find(region_name), range.len(), &offsets);
+            all_match &= result.is_match();
+            let name = range.name(binary_name);
+            println!("\t[{}] <- '{}': {}", region_name, name, result);
+        }
+    }
+    Ok(all_match)
+}
 
+// Generate the partition layout of every binary found, in each 'format[=file]'
+// requested
+fn export_layout<'a, I>(
+    exports: I,
+    puzzle: &PuzzleDisplay,
+    config: &ExportConfig,
+) -> anyhow::Result<()>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let found: Vec<Region> = puzzle
+        .pieces()
+        .iter()
+        .filter(|p| p.kind() == PieceKind::Binary)
+        .map(|p| Region::new(p.name().to_string(), p.start(), p.len()))
+        .collect();
+    let regions = export::align_regions(&found, config.erase_size, config.flash_size);
+    for (a, b) in export::overlaps(&regions) {
+        let s = format!(
+            "➜ Warning: '{}' and '{}' overlap once aligned on {:#x}",
+            a.name(),
+            b.name(),
+            config.erase_size
+        );
+        println!("{}", s.yellow().bold());
+    }
+
+    for export in exports {
+        let (format, file) = match export.split_once('=') {
+            Some((format, file)) => (format, Some(file)),
+            None => (export, None),
+        };
+        let format: ExportFormat = format.parse()?;
+        let content = export::export(format, &regions, config);
+        match file {
+            Some(file) => {
+                fs::write(file, content + "\n")?;
+                println!("{}", format!("➜ Layout written to '{}'", file).bold());
+            }
+            None => println!("{}\n{}", format!("➜ Layout as {}:", export).bold(), content),
+        }
+    }
+    Ok(())
+}
+
+// Outcome of the search, on which the commands work
+struct Search {
+    flash_image: Arc<FlashImage>,
+    layout: Arc<Option<Layout>>,
+    puzzle: Arc<Mutex<PuzzleDisplay>>,
+    bsize: usize,
+    erase_size: usize,
+}
+
+// Search the binaries in the flash image, and place the pieces found
+fn search(matches: &ArgMatches) -> anyhow::Result<Search> {
     // mandatory arguments
     let flash_img = matches.value_of("flash_image").unwrap();
-    let bin_list = matches.values_of("binaries_list").unwrap();
+    let bin_list: Vec<&str> = matches
+        .values_of("binaries_list")
+        .map(|v| v.collect())
+        .unwrap_or_default();
     // argument with default value
-    let bsize: usize = matches.value_of("bsize").unwrap().parse::<usize>()?;
+    let bsize = parse_size(matches.value_of("bsize").unwrap())?;
+    let erase_size = parse_size(matches.value_of("erase_size").unwrap())?;
+    if erase_size == 0 {
+        bail!("the erase block size cannot be null");
+    }
+    let load_base = match matches.value_of("load_base") {
+        Some(base) => Some(parse_size(base)? as u64),
+        None => None,
+    };
     // optional arguments
     let v_scale = matches.value_of("v_scale");
     let h_scale = matches.value_of("h_scale");
@@ -37,8 +190,25 @@ fn main() -> anyhow::Result<()> {
     #[cfg(debug_assertions)]
     let mut now = Instant::now();
 
+    // a chip read with the wrong size gives repeated copies of its content
+    let mut image = FlashImage::new(flash_img, bsize)?;
+    if let Some(size) = image.mirror_size() {
+        let s = format!(
+            "➜ Warning: the flash image is made of {} identical copies of its first {:#x} bytes, \
+            was the chip read with the wrong size or address lines?",
+            image.size() as usize / size,
+            size
+        );
+        println!("{}", s.yellow().bold());
+        if matches.is_present("first_copy") {
+            println!("\tonly the first copy is searched");
+            image = FlashImage::from_data(image.data()[..size].to_vec(), bsize);
+        } else {
+            println!("\tsee '--first_copy' to only search the first copy");
+        }
+    }
     // RO thread-shared structure
-    let flash_image = Arc::new(FlashImage::new(flash_img, bsize)?);
+    let flash_image = Arc::new(image);
     #[cfg(debug_assertions)]
     {
         let elapsed = now.elapsed();
@@ -46,6 +216,12 @@ fn main() -> anyhow::Result<()> {
         now = Instant::now();
     }
 
+    // RO thread-shared layout
+    let layout = Arc::new(load_layout(matches, &flash_image)?);
+    if matches.is_present("expect") && layout.is_none() {
+        bail!("checking expectations requires a flash layout, see '--dtb' or '--layout'");
+    }
+
     // create a mutable thread-shared PuzzleDisplay
     let puzzle = Arc::new(Mutex::new(PuzzleDisplay::new(
         &flash_image,
@@ -53,45 +229,368 @@ fn main() -> anyhow::Result<()> {
         h_scale,
     )));
 
+    // RO thread-shared UEFI content
+    let uefi_entries = Arc::new(if matches.is_present("uefi") {
+        uefi::scan(flash_image.data())
+    } else {
+        Vec::new()
+    });
+
+    // RO thread-shared SquashFS filesystems
+    let filesystems = Arc::new(if matches.is_present("squashfs") {
+        squashfs::scan(flash_image.data())
+    } else {
+        Vec::new()
+    });
+
+    // RO thread-shared JFFS2 filesystems
+    let jffs2_filesystems = Arc::new(if matches.is_present("jffs2") {
+        jffs2::scan(flash_image.data(), erase_size)
+    } else {
+        Vec::new()
+    });
+
+    // the layout regions are added first, so that they are drawn on the left
+    if let Some(layout) = layout.as_ref() {
+        print!("{}", layout);
+        let mut puzzle = puzzle.lock().unwrap();
+        for region in layout.regions() {
+            let p = PuzzlePiece::region(region.name().to_string(), region.size(), region.offset());
+            // regions outside of the dump cannot be drawn
+            if puzzle.add_element(p).is_err() {
+                println!("\t'{}' is out of the flash image", region.name());
+            }
+        }
+    }
+    if matches.is_present("uefi") {
+        println!("{}", "➜ UEFI firmware volumes:".bold());
+        print!("{}", uefi::summary(&uefi_entries));
+        let mut puzzle = puzzle.lock().unwrap();
+        for entry in uefi_entries.iter().filter(|e| e.depth() == 0) {
+            let p = PuzzlePiece::region(entry.label(), entry.size(), entry.offset().unwrap());
+            if puzzle.add_element(p).is_err() {
+                println!("\t'{}' is out of the flash image", entry.label());
+            }
+        }
+    }
+    if matches.is_present("squashfs") {
+        println!("{}", "➜ SquashFS filesystems:".bold());
+        let mut puzzle = puzzle.lock().unwrap();
+        for fs in filesystems.iter() {
+            println!(
+                "\t{:#010x} - {:#010x}\t{} compressed, {} files",
+                fs.offset(),
+                fs.offset() + fs.size(),
+                fs.compression(),
+                fs.files().len()
+            );
+            let name = format!("squashfs ({})", fs.compression());
+            if puzzle
+                .add_element(PuzzlePiece::region(name.clone(), fs.size(), fs.offset()))
+                .is_err()
+            {
+                println!("\t'{}' is out of the flash image", name);
+            }
+        }
+    }
+    if matches.is_present("jffs2") {
+        println!("{}", "➜ JFFS2 filesystems:".bold());
+        let mut puzzle = puzzle.lock().unwrap();
+        for fs in jffs2_filesystems.iter() {
+            println!(
+                "\t{:#010x} - {:#010x}\t{} endian, {} nodes ({} with bad CRC), {} files",
+                fs.offset(),
+                fs.offset() + fs.size(),
+                if fs.is_big_endian() { "big" } else { "little" },
+                fs.nodes(),
+                fs.bad_crc(),
+                fs.files().len()
+            );
+            let p = PuzzlePiece::region("jffs2".to_string(), fs.size(), fs.offset());
+            if puzzle.add_element(p).is_err() {
+                println!("\t'jffs2' is out of the flash image");
+            }
+        }
+    }
+    if matches.is_present("android") {
+        println!("{}", "➜ Android boot images:".bold());
+        let mut puzzle = puzzle.lock().unwrap();
+        for boot in android::scan(flash_image.data()) {
+            println!(
+                "\t{:#010x} - {:#010x}\theader v{}, page size {}",
+                boot.offset(),
+                boot.offset() + boot.size(),
+                boot.version(),
+                boot.page_size()
+            );
+            for section in boot.sections() {
+                println!(
+                    "\t\t{:<14}from {:#010x} to {:#010x}",
+                    section.name(),
+                    section.offset(),
+                    section.offset() + section.size()
+                );
+                let name = format!("boot {}", section.name());
+                let p = PuzzlePiece::region(name.clone(), section.size(), section.offset());
+                if puzzle.add_element(p).is_err() {
+                    println!("\t'{}' is out of the flash image", name);
+                }
+            }
+        }
+    }
+
+    if matches.is_present("uboot_env") {
+        println!("{}", "➜ U-Boot environments:".bold());
+        let mut puzzle = puzzle.lock().unwrap();
+        for env in ubootenv::scan(flash_image.data()) {
+            let format = match env.flag() {
+                Some(flag) => format!("redundant (flag {:#04x})", flag),
+                None => "single".to_string(),
+            };
+            let crc = if !env.is_crc_ok() {
+                "bad CRC".red().to_string()
+            } else if env.is_big_endian() {
+                "big endian CRC".to_string()
+            } else {
+                "little endian CRC".to_string()
+            };
+            println!(
+                "\t{:#010x} - {:#010x}\t{}, {}, {} variables",
+                env.offset(),
+                env.offset() + env.size(),
+                format,
+                crc,
+                env.vars().len()
+            );
+            for (key, value) in env.vars() {
+                println!("\t\t{}={}", key, value);
+            }
+            let mut name = match env.flag() {
+                Some(_) => "u-boot env (redundant)".to_string(),
+                None => "u-boot env".to_string(),
+            };
+            if !env.is_crc_ok() {
+                name.push_str(" (bad CRC)");
+            }
+            let p = PuzzlePiece::region(name.clone(), env.size(), env.offset());
+            if puzzle.add_element(p).is_err() {
+                println!("\t'{}' is out of the flash image", name);
+            }
+        }
+    }
+    if matches.is_present("duplicates") {
+        println!("{}", "➜ Duplicated regions:".bold());
+        let min_size = parse_size(matches.value_of("duplicate_min_size").unwrap())?;
+        let mut puzzle = puzzle.lock().unwrap();
+        for (n, (size, offsets)) in flash_image.duplicates(bsize, min_size).iter().enumerate() {
+            let list: Vec<String> = offsets.iter().map(|o| format!("{:#010x}", o)).collect();
+            println!("\t{:#x} bytes at {}", size, list.join(", "));
+            for (i, offset) in offsets.iter().enumerate() {
+                let name = format!("duplicate {}, copy {}/{}", n, i + 1, offsets.len());
+                let p = PuzzlePiece::region(name.clone(), *size, *offset);
+                if puzzle.add_element(p).is_err() {
+                    println!("\t'{}' is out of the flash image", name);
+                }
+            }
+        }
+    }
+
     // thread 'pool'
     let mut threads: Vec<_> = Vec::new();
 
-    for binary_name in bin_list {
-        let binary_name = binary_name.to_string();
+    // directories and glob patterns hold several files, and archives hold
+    // several binaries
+    let filter = Filter::new(
+        matches.values_of("include").into_iter().flatten(),
+        matches.values_of("exclude").into_iter().flatten(),
+        parse_size(matches.value_of("min_size").unwrap())? as u64,
+    )?;
+    let mut binaries = Vec::new();
+    for path in paths::expand(bin_list.into_iter(), &filter)? {
+        binaries.extend(input::load_binaries(&path)?);
+    }
+    // identical binaries are searched once
+    let binaries = input::dedup(binaries);
+
+    for binary in binaries {
         // clone shared references
         let flash_image: Arc<FlashImage> = Arc::clone(&flash_image);
         let puzzle = Arc::clone(&puzzle);
+        let layout = Arc::clone(&layout);
+        let uefi_entries = Arc::clone(&uefi_entries);
+        let filesystems = Arc::clone(&filesystems);
+        let jffs2_filesystems = Arc::clone(&jffs2_filesystems);
         // here is the thread
         let handle = thread::spawn(move || -> thread::Result<()> {
-            let valid_offsets = flash_image.seek_image(&*binary_name, bsize).unwrap();
-            if valid_offsets.is_empty() {
-                let s = format!("➜ '{}' not found in flash image...", binary_name);
-                println!("{}", s.bold());
-            } else {
-                let mut s = format!("➜ '{}' found in flash image:\n", binary_name)
-                    .bold()
-                    .to_string();
-
-                // FIXME: replaced '?' by 'unwrap()' because was unable to transform
-                // custom Error into thread::Error
-                // TODO: use anyhow::Error everywhere instead of thiserror
-                let file_size = fs::metadata(&*binary_name)
-                    .unwrap()
-                    .len()
-                    .try_into()
-                    .unwrap();
-                for offset in valid_offsets.iter() {
+            // FIXME: replaced '?' by 'unwrap()' because was unable to transform
+            // custom Error into thread::Error
+            // TODO: use anyhow::Error everywhere instead of thiserror
+            // binaries made of several blocks of memory are searched by parts
+            for range in binary.ranges() {
+                let valid_offsets = flash_image.seek_data(range.data(), bsize);
+                let name = range.name(binary.name());
+                let aliases: Vec<String> = binary.aliases().iter().map(|a| range.name(a)).collect();
+                // identical binaries are reported once, under all their names
+                let binary_name = std::iter::once(&name)
+                    .chain(aliases.iter())
+                    .map(|n| n.as_str())
+                    .collect::<Vec<_>>()
+                    .join("' = '");
+                let binary = range.data();
+                if valid_offsets.is_empty() {
+                    let s = format!("➜ '{}' not found in flash image...", binary_name);
+                    println!("{}", s.bold());
+                } else {
+                    let mut s = format!("➜ '{}' found in flash image:\n", binary_name)
+                        .bold()
+                        .to_string();
+                    let file_size = binary.len();
+                    for offset in valid_offsets.iter() {
+                        s.push_str(&format!(
+                            "\tfrom {:#010x} to {:#010x}",
+                            offset,
+                            offset + file_size
+                        ));
+                        if let Some(layout) = layout.as_ref() {
+                            for region in layout.locate(*offset, file_size) {
+                                s.push_str(&format!(" [{}]", region.name()));
+                            }
+                        }
+                        if let (Some(address), Some(load_base)) = (range.address(), load_base) {
+                            s.push_str(&check_load_address(address, load_base, *offset));
+                        }
+                        s.push('\n');
+                        let p =
+                            PuzzlePiece::aliased(name.clone(), aliases.clone(), file_size, *offset);
+                        puzzle.lock().unwrap().add_element(p).unwrap();
+                    }
+                    print!("{}", s);
+                };
+
+                if !uefi_entries.is_empty() {
+                    let uefi_matches = uefi::find(&uefi_entries, binary);
+                    if !uefi_matches.is_empty() {
+                        let mut s = format!("➜ '{}' found in UEFI firmware:\n", binary_name)
+                            .bold()
+                            .to_string();
+                        for m in uefi_matches.iter() {
+                            let what = if m.body_only { "content of " } else { "" };
+                            let desc = uefi::describe(&uefi_entries, m.entry);
+                            match m.offset {
+                                Some(offset) => {
+                                    s.push_str(&format!(
+                                        "\t{}{} from {:#010x} to {:#010x}\n",
+                                        what,
+                                        desc,
+                                        offset,
+                                        offset + binary.len()
+                                    ));
+                                    // already drawn if found by the block search
+                                    if !valid_offsets.contains(&offset) {
+                                        let p = PuzzlePiece::aliased(
+                                            name.clone(),
+                                            aliases.clone(),
+                                            binary.len(),
+                                            offset,
+                                        );
+                                        puzzle.lock().unwrap().add_element(p).unwrap();
+                                    }
+                                }
+                                None => s.push_str(&format!("\t{}{} (compressed)\n", what, desc)),
+                            }
+                        }
+                        print!("{}", s);
+                    }
+                }
+
+                let files = squashfs::find(&filesystems, flash_image.data(), binary);
+                if !files.is_empty() {
+                    let mut s = format!("➜ '{}' found in SquashFS:\n", binary_name)
+                        .bold()
+                        .to_string();
+                    for (fs, file) in files {
+                        s.push_str(&format!(
+                            "\t'{}' of the filesystem at {:#010x}\n",
+                            file.path(),
+                            fs.offset()
+                        ));
+                    }
+                    print!("{}", s);
+                }
+
+                let files = jffs2::find(&jffs2_filesystems, flash_image.data(), binary);
+                if !files.is_empty() {
+                    let mut s = format!("➜ '{}' found in JFFS2:\n", binary_name)
+                        .bold()
+                        .to_string();
+                    for (fs, file) in files {
+                        s.push_str(&format!(
+                            "\t'{}' of the filesystem at {:#010x}\n",
+                            file.path(),
+                            fs.offset()
+                        ));
+                    }
+                    print!("{}", s);
+                }
+            }
+
+            Ok(())
+        });
+        threads.push(handle);
+    }
+
+    // artefacts only known by their digest: hash the candidate contents once
+    // for each size
+    let mut artefacts: BTreeMap<usize, Vec<Artefact>> = BTreeMap::new();
+    if let Some(manifest_path) = matches.value_of("manifest") {
+        for artefact in manifest::load(manifest_path)? {
+            artefacts.entry(artefact.size()).or_default().push(artefact);
+        }
+    }
+    for (size, group) in artefacts {
+        let flash_image: Arc<FlashImage> = Arc::clone(&flash_image);
+        let puzzle = Arc::clone(&puzzle);
+        let layout = Arc::clone(&layout);
+        let handle = thread::spawn(move || -> thread::Result<()> {
+            let digests: Vec<&[u8; 32]> = group.iter().map(|a| a.sha256()).collect();
+            let found = flash_image.seek_sha256(size, &digests);
+            for (i, artefact) in group.iter().enumerate() {
+                let offsets: Vec<usize> = found
+                    .iter()
+                    .filter(|(j, _)| *j == i)
+                    .map(|(_, offset)| *offset)
+                    .collect();
+                if offsets.is_empty() {
+                    let s = format!(
+                        "➜ '{}' (by digest) not found in flash image...",
+                        artefact.name()
+                    );
+                    println!("{}", s.bold());
+                    continue;
+                }
+                let mut s = format!(
+                    "➜ '{}' (by digest) found in flash image:\n",
+                    artefact.name()
+                )
+                .bold()
+                .to_string();
+                for offset in offsets {
                     s.push_str(&format!(
-                        "\tfrom {:#010x} to {:#010x}\n",
+                        "\tfrom {:#010x} to {:#010x}",
                         offset,
-                        offset + file_size
+                        offset + size
                     ));
-                    let p = PuzzlePiece::new(binary_name.to_string(), file_size, *offset);
+                    if let Some(layout) = layout.as_ref() {
+                        for region in layout.locate(offset, size) {
+                            s.push_str(&format!(" [{}]", region.name()));
+                        }
+                    }
+                    s.push('\n');
+                    let p = PuzzlePiece::new(artefact.name().to_string(), size, offset);
                     puzzle.lock().unwrap().add_element(p).unwrap();
                 }
                 print!("{}", s);
-            };
-
+            }
             Ok(())
         });
         threads.push(handle);
@@ -107,7 +606,397 @@ fn main() -> anyhow::Result<()> {
     {
         let elapsed = now.elapsed();
         println!("All binaries processed: {:.2?}", elapsed);
-        now = Instant::now();
+    }
+
+    // the binaries and regions found may be U-Boot images: their CRCs and
+    // hashes tell whether they are complete, as only their first blocks match
+    let pieces = puzzle.lock().unwrap().pieces().to_vec();
+    let uboot_images = uimage::scan(flash_image.data(), pieces.iter().map(|p| p.start()));
+    if !uboot_images.is_empty() {
+        println!("{}", "➜ U-Boot images:".bold());
+    }
+    let address = |a: Option<u64>| a.map_or("-".to_string(), |a| format!("{:#010x}", a));
+    for image in uboot_images.iter() {
+        println!(
+            "\t{:#010x} - {:#010x}\t{} {}",
+            image.offset(),
+            image.offset() + image.size(),
+            image.format(),
+            if image.is_corrupted() {
+                "CORRUPTED".red()
+            } else {
+                "OK".green()
+            }
+        );
+        for sub in image.images() {
+            println!(
+                "\t\t'{}': {}, {} compression, {:#x} bytes, load {}, entry {}",
+                sub.name(),
+                sub.kind(),
+                sub.compression(),
+                sub.size(),
+                address(sub.load()),
+                address(sub.entry())
+            );
+            for check in sub.checks() {
+                let result = match check.valid() {
+                    Some(true) => "OK".green(),
+                    Some(false) => "BAD".red(),
+                    None => "unchecked".normal(),
+                };
+                println!("\t\t\t{} {}", check.name(), result);
+            }
+        }
+        if image.is_corrupted() {
+            for p in pieces
+                .iter()
+                .filter(|p| p.kind() == PieceKind::Binary && p.start() == image.offset())
+            {
+                let s = format!(
+                    "\t'{}' matches the first blocks of a corrupted image",
+                    p.name()
+                );
+                println!("{}", s.red().bold());
+            }
+        }
+    }
+
+    Ok(Search {
+        flash_image,
+        layout,
+        puzzle,
+        bsize,
+        erase_size,
+    })
+}
+
+// 'extract' command: carve the pieces found, and the gaps between them
+fn extract(matches: &ArgMatches) -> anyhow::Result<()> {
+    if matches.is_present("first_copy") {
+        bail!("'--first_copy' would only carve the first copy of the flash image");
+    }
+    let search = search(matches)?;
+    let out_dir = Path::new(matches.value_of("output").unwrap());
+    let puzzle = search.puzzle.lock().unwrap();
+    let data = search.flash_image.data();
+
+    let carvings = extract::extract(data, puzzle.pieces(), out_dir)?;
+    let flash_img = matches.value_of("flash_image").unwrap();
+    let index = extract::index(flash_img, data.len(), &carvings);
+    fs::write(out_dir.join("index.json"), index + "\n")?;
+    let s = format!(
+        "➜ {} files and their index written to '{}'",
+        carvings.len(),
+        out_dir.display()
+    );
+    println!("{}", s.bold());
+    for carving in carvings.iter() {
+        println!("\t{}", carving.file());
+    }
+    println!("{}", puzzle);
+    Ok(())
+}
+
+// 'assemble' command: build a flash image from a layout of binaries
+fn assemble(matches: &ArgMatches) -> anyhow::Result<()> {
+    let placements = assemble::load_layout(matches.value_of("assemble_layout").unwrap())?;
+    let size = match matches.value_of("image_size") {
+        Some(size) => parse_size(size)?,
+        None => placements.iter().map(|p| p.end()).max().unwrap_or(0),
+    };
+    let fill = parse_size(matches.value_of("fill").unwrap())?;
+    let fill = u8::try_from(fill).map_err(|_| anyhow!("the fill value must be a byte"))?;
+    let image = assemble::assemble(&placements, size, fill, matches.is_present("allow_overlap"))?;
+
+    let output = matches.value_of("output").unwrap();
+    fs::write(output, &image)?;
+    let s = format!("➜ '{}' assembled ({:#x} bytes):", output, image.len());
+    println!("{}", s.bold());
+
+    // draw the image with the same pieces as a search would
+    let flash_image = FlashImage::from_data(image, 512);
+    let mut puzzle = PuzzleDisplay::new(
+        &flash_image,
+        matches.value_of("v_scale"),
+        matches.value_of("h_scale"),
+    );
+    for p in placements.iter() {
+        println!(
+            "\tfrom {:#010x} to {:#010x}\t'{}'",
+            p.offset(),
+            p.end(),
+            p.name()
+        );
+        puzzle.add_element(PuzzlePiece::new(p.name().to_string(), p.len(), p.offset()))?;
+    }
+    if !puzzle.is_empty() {
+        println!("{}", puzzle);
+    }
+    Ok(())
+}
+
+// load a binary which must be a single block of data
+fn load_single(path: &str) -> anyhow::Result<Vec<u8>> {
+    let mut ranges = input::load(path)?;
+    if ranges.len() != 1 {
+        bail!("'{}' is made of {} separate ranges", path, ranges.len());
+    }
+    Ok(ranges.remove(0).into_data())
+}
+
+// 'replace' command: write a new binary in place of an old one
+fn replace(matches: &ArgMatches) -> anyhow::Result<()> {
+    if matches.is_present("first_copy") {
+        bail!("'--first_copy' would only write the first copy of the flash image");
+    }
+    let flash_img = matches.value_of("flash_image").unwrap();
+    let output = matches.value_of("output").unwrap();
+    if Path::new(output).exists() && fs::canonicalize(output)? == fs::canonicalize(flash_img)? {
+        bail!("the flash image cannot be modified in place, choose another output");
+    }
+    let old_name = matches.value_of("old").unwrap();
+    let new_name = matches.value_of("new").unwrap();
+    let old = load_single(old_name)?;
+    let new = load_single(new_name)?;
+    let fill = parse_size(matches.value_of("fill").unwrap())?;
+    let fill = u8::try_from(fill).map_err(|_| anyhow!("the fill value must be a byte"))?;
+
+    // the other pieces found bound the space available to the new binary
+    let search = search(matches)?;
+    let offsets = search.flash_image.seek_data(&old, search.bsize);
+    let offset = match offsets[..] {
+        [offset] => offset,
+        [] => bail!("'{}' not found in flash image", old_name),
+        _ => bail!(
+            "'{}' found {} times in flash image",
+            old_name,
+            offsets.len()
+        ),
+    };
+    let puzzle = search.puzzle.lock().unwrap();
+    let image_size = search.flash_image.data().len();
+    let (limit, bound) = replace::available(puzzle.pieces(), offset, old.len(), image_size);
+    if offset + new.len() > limit {
+        match bound {
+            Some(p) => bail!(
+                "'{}' would cross into '{}' at {:#010x}",
+                new_name,
+                p.name(),
+                limit
+            ),
+            None => bail!("'{}' would cross the end of the flash image", new_name),
+        }
+    }
+
+    let mut image = search.flash_image.data().to_vec();
+    replace::replace(&mut image, offset, old.len(), &new, fill, limit)?;
+    fs::write(output, &image)?;
+    let s = format!(
+        "➜ '{}' replaced by '{}' at {:#010x}, written to '{}'",
+        old_name, new_name, offset, output
+    );
+    println!("{}", s.bold());
+    println!(
+        "\tfrom {:#010x} to {:#010x}, {:#x} bytes available",
+        offset,
+        offset + new.len(),
+        limit - offset
+    );
+    Ok(())
+}
+
+// 'diff' command: draw the blocks differing from another flash image, along
+// with the binaries and regions found
+fn diff(matches: &ArgMatches) -> anyhow::Result<()> {
+    let flash_img = matches.value_of("flash_image").unwrap();
+    let other_img = matches.value_of("against").unwrap();
+    let search = search(matches)?;
+    let other = FlashImage::new(other_img, search.bsize)?;
+    if other.size() != search.flash_image.size() {
+        let s = format!(
+            "➜ Warning: '{}' is {:#x} bytes, '{}' is {:#x} bytes",
+            flash_img,
+            search.flash_image.size(),
+            other_img,
+            other.size()
+        );
+        println!("{}", s.yellow().bold());
+    }
+
+    let changes = search.flash_image.diff(&other, search.bsize);
+    let mut puzzle = search.puzzle.lock().unwrap();
+    let found = puzzle.pieces().to_vec();
+    let s = if changes.is_empty() {
+        format!(
+            "➜ No block differs between '{}' and '{}'",
+            flash_img, other_img
+        )
+    } else {
+        format!(
+            "➜ Blocks differing between '{}' and '{}':",
+            flash_img, other_img
+        )
+    };
+    println!("{}", s.bold());
+    for (offset, size) in changes {
+        // the binaries and regions holding the change
+        let touched: Vec<String> = found
+            .iter()
+            .filter(|p| p.start() < offset + size && offset < p.start() + p.len())
+            .map(|p| match p.kind() {
+                PieceKind::Region => format!("[{}]", p.name()),
+                _ => format!("'{}'", p.name()),
+            })
+            .collect();
+        let mut line = format!("\tfrom {:#010x} to {:#010x}", offset, offset + size);
+        if !touched.is_empty() {
+            line = format!("{}\tin {}", line, touched.join(", "));
+        }
+        println!("{}", line);
+        // the changes past the end of the image cannot be drawn
+        if (offset as u64) < search.flash_image.size() {
+            puzzle.add_element(PuzzlePiece::change("changed", size, offset))?;
+        }
+    }
+    if !puzzle.is_empty() {
+        println!("{}", puzzle);
+    }
+    Ok(())
+}
+
+// 'consensus' command: find the blocks common to the dumps of several units,
+// and the per-unit ones
+fn consensus(matches: &ArgMatches) -> anyhow::Result<()> {
+    let image_names: Vec<&str> = matches.values_of("images").unwrap().collect();
+    let bsize = parse_size(matches.value_of("bsize").unwrap())?;
+    let images = image_names
+        .iter()
+        .map(|name| FlashImage::new(name, bsize))
+        .collect::<Result<Vec<_>, _>>()?;
+    let largest = images.iter().max_by_key(|i| i.size()).unwrap();
+    let size = largest.size() as usize;
+    if images.iter().any(|i| i.size() != largest.size()) {
+        let s =
+            "➜ Warning: the dumps are not the same size, the end of the shorter ones is per-unit";
+        println!("{}", s.yellow().bold());
+    }
+
+    let refs: Vec<&FlashImage> = images.iter().collect();
+    let spans = consensus::split(&seeker::varying_blocks(&refs, bsize), size);
+    let s = format!("➜ Consensus of {} dumps:", images.len());
+    println!("{}", s.bold());
+    let mut puzzle = PuzzleDisplay::new(
+        largest,
+        matches.value_of("v_scale"),
+        matches.value_of("h_scale"),
+    );
+    for span in spans.iter() {
+        let kind = if span.is_per_unit() {
+            "per-unit"
+        } else {
+            "common"
+        };
+        println!(
+            "\tfrom {:#010x} to {:#010x}\t{}",
+            span.offset(),
+            span.end(),
+            kind
+        );
+        if span.is_per_unit() {
+            puzzle.add_element(PuzzlePiece::change(kind, span.size(), span.offset()))?;
+        }
+    }
+    if let Some(output) = matches.value_of("output") {
+        fs::write(
+            output,
+            consensus::report(&image_names, bsize, size, &spans) + "\n",
+        )?;
+        let s = format!("➜ Regions written to '{}'", output);
+        println!("{}", s.bold());
+    }
+    if !puzzle.is_empty() {
+        println!("{}", puzzle);
+    }
+    Ok(())
+}
+
+// 'verify' command: check the flash image against a manifest of the binaries
+// expected at each offset
+fn verify(matches: &ArgMatches) -> anyhow::Result<()> {
+    let flash_img = matches.value_of("flash_image").unwrap();
+    let manifest = matches.value_of("manifest").unwrap();
+    let bsize = parse_size(matches.value_of("bsize").unwrap())?;
+    let flash_image = FlashImage::new(flash_img, bsize)?;
+    let verdicts = verify::verify(manifest, &flash_image, bsize)?;
+    if verdicts.is_empty() {
+        bail!("no binary listed in '{}'", manifest);
+    }
+
+    let s = format!("➜ '{}' verified against '{}':", flash_img, manifest);
+    println!("{}", s.bold());
+    let width = verdicts
+        .iter()
+        .filter_map(|(_, name, _)| name.as_ref().map(|n| n.len() + 2))
+        .max()
+        .unwrap_or(0)
+        .max("EXPECTED".len());
+    println!("\t{:<10}  {:<width$}  RESULT", "OFFSET", "EXPECTED");
+    for (offset, name, verdict) in verdicts.iter() {
+        let name = name
+            .as_ref()
+            .map_or("-".to_string(), |n| format!("'{}'", n));
+        println!("\t{:#010x}  {:<width$}  {}", offset, name, verdict);
+    }
+
+    let failed = verify::failures(&verdicts);
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, verdicts.len());
+    }
+    let s = format!("➜ All {} binaries in place", verdicts.len());
+    println!("{}", s.green().bold());
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let matches = cli::build_cli().get_matches();
+    match matches.subcommand() {
+        ("extract", Some(matches)) => return extract(matches),
+        ("assemble", Some(matches)) => return assemble(matches),
+        ("replace", Some(matches)) => return replace(matches),
+        ("verify", Some(matches)) => return verify(matches),
+        ("diff", Some(matches)) => return diff(matches),
+        ("consensus", Some(matches)) => return consensus(matches),
+        _ => (),
+    }
+
+    let Search {
+        flash_image,
+        layout,
+        puzzle,
+        bsize,
+        erase_size,
+    } = search(&matches)?;
+    #[cfg(debug_assertions)]
+    let now = Instant::now();
+
+    let mut all_match = true;
+    if let (Some(expectations), Some(layout)) = (matches.values_of("expect"), layout.as_ref()) {
+        all_match = check_expectations(expectations, layout, &flash_image, bsize)?;
+    }
+
+    if let Some(exports) = matches.values_of("export") {
+        let flash_img = matches.value_of("flash_image").unwrap();
+        let image_name = Path::new(flash_img)
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| flash_img.to_string());
+        let config = ExportConfig {
+            erase_size,
+            flash_size: flash_image.size() as usize,
+            image_name: &image_name,
+            mtd_id: matches.value_of("mtd_id").unwrap(),
+        };
+        export_layout(exports, &puzzle.lock().unwrap(), &config)?;
     }
 
     // display the flash layout
@@ -120,5 +1009,8 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    if !all_match {
+        bail!("the flash layout does not hold the expected binaries");
+    }
     Ok(())
 }
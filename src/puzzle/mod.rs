@@ -14,13 +14,27 @@ use corner::{ClockWiseSlots, Corner};
 use dynzip::DynamicZip;
 use parray::{slot::SlotStatus, PieceArray};
 use std::fmt;
-use term_size;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceKind {
+    /// A binary found in the flash image
+    Binary,
+    /// A region of the flash layout (e.g. a partition)
+    Region,
+    /// A range of blocks differing between flash images
+    Change,
+}
 
 #[derive(Debug, Clone)]
 pub struct PuzzlePiece {
     bin_name: String,
     bin_size: usize,
     bin_offset: usize,
+    // other names of an identical binary
+    aliases: Vec<String>,
+    kind: PieceKind,
+    // index of the smallest piece of the same kind holding this one
+    parent: Option<usize>,
 }
 
 impl PuzzlePiece {
@@ -29,24 +43,75 @@ impl PuzzlePiece {
             bin_name,
             bin_size,
             bin_offset,
+            aliases: Vec::new(),
+            kind: PieceKind::Binary,
+            parent: None,
+        }
+    }
+
+    /// A binary found under several names, as files with the same content
+    pub fn aliased(
+        bin_name: String,
+        aliases: Vec<String>,
+        bin_size: usize,
+        bin_offset: usize,
+    ) -> Self {
+        PuzzlePiece {
+            aliases,
+            ..Self::new(bin_name, bin_size, bin_offset)
+        }
+    }
+
+    pub fn region(name: String, size: usize, offset: usize) -> Self {
+        PuzzlePiece {
+            bin_name: name,
+            bin_size: size,
+            bin_offset: offset,
+            aliases: Vec::new(),
+            kind: PieceKind::Region,
+            parent: None,
+        }
+    }
+
+    pub fn change(name: &str, size: usize, offset: usize) -> Self {
+        PuzzlePiece {
+            bin_name: format!("{} {:#010x}-{:#010x}", name, offset, offset + size),
+            bin_size: size,
+            bin_offset: offset,
+            aliases: Vec::new(),
+            kind: PieceKind::Change,
+            parent: None,
         }
     }
 
-    fn start(&self) -> usize {
+    pub fn start(&self) -> usize {
         self.bin_offset
     }
 
-    fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.bin_size
     }
 
-    fn name(&self) -> &str {
+    pub fn name(&self) -> &str {
         self.bin_name.as_str()
     }
+
+    pub fn kind(&self) -> PieceKind {
+        self.kind
+    }
+
+    /// Index of the piece holding this one (e.g. a FIT image holding a
+    /// kernel), if any
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
 }
 
 const COLOR_LIST: [&str; 7] = ["red", "green", "yellow", "blue", "magenta", "cyan", "white"];
 
+// terminal size (width, height) assumed when the output is not a terminal
+const DEFAULT_TERM_SIZE: (usize, usize) = (160, 48);
+
 #[derive(Debug)]
 enum Scaling {
     Dynamic,
@@ -57,13 +122,16 @@ impl Scaling {
     fn get_v_scale(&self, display: &PuzzleDisplay) -> usize {
         match self {
             Scaling::Dynamic => {
-                let (_, term_h) = term_size::dimensions().unwrap();
+                let (_, term_h) = term_size::dimensions().unwrap_or(DEFAULT_TERM_SIZE);
                 let table_h = display.parray.array.nrows();
                 // (table_h + 1) + (table_h * scale) + footer = target_height
                 // table_h * (scale + 1) + 1 + n_image = term_h / 2
                 // scale = (term_h / 2 - 1 - n_image) / table_h - 1
-                // FIXME: may fail if not enough space in terminal
-                let scale = (term_h / 2 - 1 - display.pieces.len()) / table_h - 1;
+                let scale = (term_h / 2)
+                    .saturating_sub(1 + display.pieces.len())
+                    .checked_div(table_h)
+                    .unwrap_or(0)
+                    .saturating_sub(1);
                 std::cmp::max(scale, 1)
             }
             Scaling::Fixed(scale) => *scale,
@@ -73,13 +141,16 @@ impl Scaling {
     fn get_h_scale(&self, display: &PuzzleDisplay) -> usize {
         match self {
             Scaling::Dynamic => {
-                let (term_w, _) = term_size::dimensions().unwrap();
+                let (term_w, _) = term_size::dimensions().unwrap_or(DEFAULT_TERM_SIZE);
                 let table_w = display.parray.array.ncols();
                 // (table_w + 1) + (table_w * scale) + offset_hint = target_width
                 // table_w * (scale + 1) + 1 + 15 = term_w / 2
                 // scale = (term_w / 2 - 16) / table_w - 1
-                // FIXME: may fail if not enough space in terminal
-                let scale = (term_w / 2 - 16) / table_w - 1;
+                let scale = (term_w / 2)
+                    .saturating_sub(16)
+                    .checked_div(table_w)
+                    .unwrap_or(0)
+                    .saturating_sub(1);
                 std::cmp::max(scale, 1)
             }
             Scaling::Fixed(scale) => *scale,
@@ -143,14 +214,67 @@ impl PuzzleDisplay {
         self.pieces.is_empty()
     }
 
-    pub fn add_element(&mut self, new_piece: PuzzlePiece) -> Result<()> {
+    pub fn pieces(&self) -> &[PuzzlePiece] {
+        &self.pieces
+    }
+
+    // range of the flash image drawn for the piece
+    fn span(&self, piece: &PuzzlePiece) -> (usize, usize) {
+        let image_end = *self.parray.offset_list.last().unwrap();
+        let end = std::cmp::min(piece.start() + piece.len(), image_end);
+        (piece.start(), end)
+    }
+
+    // whether the piece `a` holds the piece `b`, of the same kind, without
+    // being the same range
+    fn holds(&self, a: &PuzzlePiece, b: &PuzzlePiece) -> bool {
+        let ((a_start, a_end), (b_start, b_end)) = (self.span(a), self.span(b));
+        a.kind == b.kind
+            && a_start <= b_start
+            && b_end <= a_end
+            && (a_start, a_end) != (b_start, b_end)
+    }
+
+    pub fn add_element(&mut self, mut new_piece: PuzzlePiece) -> Result<()> {
+        let image_end = *self.parray.offset_list.last().unwrap();
         let start_addr = new_piece.start();
-        let end_addr = start_addr + new_piece.len();
+        if start_addr >= image_end || new_piece.len() == 0 {
+            return Err(Error::OutOfImage);
+        }
+        // regions may be described past the end of a truncated dump
+        let end_addr = std::cmp::min(start_addr + new_piece.len(), image_end);
 
+        // the smallest piece holding the new one is its parent
+        new_piece.parent = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| self.holds(p, &new_piece))
+            .min_by_key(|(_, p)| p.len())
+            .map(|(i, _)| i);
+        let parent = new_piece.parent;
         self.pieces.push(new_piece);
         let piece_index = self.pieces.len() - 1;
+        self.parray
+            .add_piece(piece_index, start_addr, end_addr, parent)?;
 
-        Ok(self.parray.add_piece(piece_index, start_addr, end_addr)?)
+        // the pieces added before, which the new one holds, are nested in it
+        // unless they already are in a smaller one
+        for i in 0..piece_index {
+            let (piece, new_piece) = (&self.pieces[i], &self.pieces[piece_index]);
+            let former = piece.parent;
+            if self.holds(new_piece, piece)
+                && former.is_none_or(|f| self.holds(&self.pieces[f], new_piece))
+            {
+                self.pieces[i].parent = Some(piece_index);
+                self.parray.nest_piece(i, piece_index, former);
+            }
+        }
+        // the pieces nested in it may have left room in its parent
+        if let Some(parent) = parent {
+            self.parray.nest_piece(piece_index, parent, None);
+        }
+        Ok(())
     }
 
     pub fn display(&self) -> String {
@@ -161,13 +285,13 @@ impl PuzzleDisplay {
         let mut display = self.display_create(display_vec);
         // add footer
         self.display_create_footer(&mut display);
-        format!("{}", display)
+        display
     }
 
     fn process_columns(&self) -> Vec<Vec<String>> {
         let mut display_vec = Vec::<Vec<String>>::new();
         // create each 'filled' columns
-        for col in (&self.parray.array).columns().into_iter() {
+        for col in self.parray.array.columns() {
             let mut display_col = Vec::<String>::new();
             // begin by top border
             display_col.push("─".to_string());
@@ -199,7 +323,9 @@ impl PuzzleDisplay {
     }
 
     fn insert_edges(&self, display_vec: &mut Vec<Vec<String>>) {
-        let mut col_iter = (&self.parray.array)
+        let mut col_iter = self
+            .parray
+            .array
             .columns()
             .into_iter()
             .enumerate()
@@ -214,11 +340,7 @@ impl PuzzleDisplay {
                 } else {
                     display_col.push("─".to_string());
                 }
-                for x in column
-                    .windows(2)
-                    .into_iter()
-                    .zip(n_column.windows(2).into_iter())
-                {
+                for x in column.windows(2).into_iter().zip(n_column.windows(2)) {
                     let cwslots: ClockWiseSlots = x.try_into().unwrap();
                     let [cell_tl, cell_tr, _, _] = cwslots.inner;
                     // process cell vertical edge
@@ -273,11 +395,11 @@ impl PuzzleDisplay {
             // add the row content
             for (n, s) in row.iter().enumerate() {
                 if n % 2 == 0 {
-                    for _ in 0..self.horizontal_scale.get_h_scale(&self) {
-                        line.push_str(&s);
+                    for _ in 0..self.horizontal_scale.get_h_scale(self) {
+                        line.push_str(s);
                     }
                 } else {
-                    line.push_str(&s);
+                    line.push_str(s);
                 }
             }
             // finish by right border
@@ -295,7 +417,7 @@ impl PuzzleDisplay {
             }
             line.push('\n');
             if n % 2 == 1 {
-                for _ in 0..self.vertical_scale.get_v_scale(&self) {
+                for _ in 0..self.vertical_scale.get_v_scale(self) {
                     display.push_str(&line);
                 }
             } else {
@@ -306,13 +428,40 @@ impl PuzzleDisplay {
     }
 
     fn display_create_footer(&self, display: &mut String) {
+        // the nested pieces are listed as a tree, below their parent
         for (index, piece) in self.pieces.iter().enumerate() {
-            let color = COLOR_LIST[index % COLOR_LIST.len()];
-            let index_colored = index.to_string().color("black").on_color(color);
-            let piece_name = format!("{}: '{}'\n", index_colored.to_string(), &piece.name());
-            // TODO: add list of offsets
-            // TODO: maybe add a 'simple' print mode, to only display the footer without schema
-            display.push_str(&piece_name);
+            if piece.parent.is_none() {
+                self.display_footer_entry(display, index, 0);
+            }
+        }
+    }
+
+    fn display_footer_entry(&self, display: &mut String, index: usize, depth: usize) {
+        let piece = &self.pieces[index];
+        let color = COLOR_LIST[index % COLOR_LIST.len()];
+        let index_colored = index.to_string().color("black").on_color(color);
+        let indent = match depth {
+            0 => String::new(),
+            _ => format!("{}└─ ", "   ".repeat(depth - 1)),
+        };
+        let piece_name = match piece.kind {
+            PieceKind::Binary => {
+                let mut names = format!("'{}'", piece.name());
+                for alias in piece.aliases.iter() {
+                    names.push_str(&format!(" = '{}'", alias));
+                }
+                format!("{}{}: {}\n", indent, index_colored, names)
+            }
+            PieceKind::Region => format!("{}{}: [{}]\n", indent, index_colored, &piece.name()),
+            PieceKind::Change => format!("{}{}: <{}>\n", indent, index_colored, &piece.name()),
+        };
+        // TODO: add list of offsets
+        // TODO: maybe add a 'simple' print mode, to only display the footer without schema
+        display.push_str(&piece_name);
+        for (child, p) in self.pieces.iter().enumerate() {
+            if p.parent == Some(index) {
+                self.display_footer_entry(display, child, depth + 1);
+            }
         }
     }
 }
@@ -321,9 +470,9 @@ impl From<SlotStatus> for String {
     fn from(src: SlotStatus) -> Self {
         if src.is_used() {
             src.try_into_used()
-                .and_then(|index| {
+                .map(|index| {
                     let color = COLOR_LIST[index % COLOR_LIST.len()];
-                    Ok(index.to_string().color("black").on_color(color))
+                    index.to_string().color("black").on_color(color)
                 })
                 .unwrap()
                 .to_string()
@@ -338,3 +487,126 @@ impl fmt::Display for PuzzleDisplay {
         write!(f, "{}", self.display())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the blocks changed between dumps, as drawn by 'diff' and 'consensus',
+    // with the default scaling: the test output is not a terminal
+    #[test]
+    fn draw_changes() {
+        let flash_image = FlashImage::from_data(vec![0xff; 0x20000], 512);
+        let mut puzzle = PuzzleDisplay::new(&flash_image, None, None);
+        puzzle
+            .add_element(PuzzlePiece::change("per-unit", 0x6000, 0))
+            .unwrap();
+        puzzle
+            .add_element(PuzzlePiece::change("per-unit", 0x8000, 0x18000))
+            .unwrap();
+        assert!(puzzle
+            .add_element(PuzzlePiece::change("per-unit", 0x1000, 0x20000))
+            .is_err());
+
+        let drawing = puzzle.to_string();
+        assert!(drawing.contains("<-- 0x00006000"));
+        assert!(drawing.contains("<-- 0x00018000"));
+        assert!(drawing.contains("<per-unit 0x00000000-0x00006000>\n"));
+        assert!(drawing.contains("<per-unit 0x00018000-0x00020000>\n"));
+    }
+
+    fn drawn(puzzle: &PuzzleDisplay, index: usize) -> bool {
+        puzzle
+            .parray
+            .array
+            .iter()
+            .any(|&slot| slot == SlotStatus::Used(index))
+    }
+
+    // partitions split into sub-partitions covering all of them
+    #[test]
+    fn nested_cover_parent() {
+        let flash_image = FlashImage::from_data(vec![0xff; 0x20000], 512);
+        let pieces = [
+            PuzzlePiece::region("rootfs".to_string(), 0x10000, 0),
+            PuzzlePiece::region("rootfs_a".to_string(), 0x8000, 0),
+            PuzzlePiece::region("rootfs_b".to_string(), 0x8000, 0x8000),
+            PuzzlePiece::region("data".to_string(), 0x10000, 0x10000),
+            PuzzlePiece::region("data_a".to_string(), 0x10000, 0x10000),
+        ];
+        // the containers added first, then last
+        for order in [[0, 1, 2, 3, 4], [1, 2, 0, 4, 3]] {
+            let mut puzzle = PuzzleDisplay::new(&flash_image, Some("1"), Some("1"));
+            for &n in order.iter() {
+                puzzle.add_element(pieces[n].clone()).unwrap();
+            }
+            for index in 0..pieces.len() {
+                assert!(drawn(&puzzle, index));
+            }
+            let mut parents: Vec<(&str, Option<&str>)> = puzzle
+                .pieces()
+                .iter()
+                .map(|p| (p.name(), p.parent().map(|i| puzzle.pieces()[i].name())))
+                .collect();
+            parents.sort();
+            // a partition with the same range is not nested
+            assert_eq!(
+                parents,
+                [
+                    ("data", None),
+                    ("data_a", None),
+                    ("rootfs", None),
+                    ("rootfs_a", Some("rootfs")),
+                    ("rootfs_b", Some("rootfs"))
+                ]
+            );
+        }
+    }
+
+    // columns of the drawing holding the piece
+    fn columns(puzzle: &PuzzleDisplay, index: usize) -> Vec<usize> {
+        puzzle
+            .parray
+            .array
+            .columns()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, col)| col.iter().any(|&slot| slot == SlotStatus::Used(index)))
+            .map(|(n, _)| n)
+            .collect()
+    }
+
+    // a binary found in another one, e.g. a kernel in a FIT image
+    #[test]
+    fn nested_binary() {
+        let flash_image = FlashImage::from_data(vec![0xff; 0x20000], 512);
+        let fit = PuzzlePiece::new("image.itb".to_string(), 0x10000, 0x10000);
+        let kernel = PuzzlePiece::new("Image".to_string(), 0x8000, 0x10000);
+        // the container added first, then last
+        for (pieces, (parent, child)) in [
+            ([fit.clone(), kernel.clone()], (0, 1)),
+            ([kernel.clone(), fit.clone()], (1, 0)),
+        ] {
+            let mut puzzle = PuzzleDisplay::new(&flash_image, Some("1"), Some("1"));
+            for piece in pieces {
+                puzzle.add_element(piece).unwrap();
+            }
+            assert_eq!(puzzle.pieces()[child].parent(), Some(parent));
+            assert_eq!(puzzle.pieces()[parent].parent(), None);
+            // drawn in the column of the container, whose end stays visible
+            assert_eq!(columns(&puzzle, child), [0]);
+            assert_eq!(columns(&puzzle, parent), [0]);
+
+            let index = |n: usize| {
+                let color = COLOR_LIST[n % COLOR_LIST.len()];
+                n.to_string().color("black").on_color(color)
+            };
+            let footer = format!(
+                "{}: 'image.itb'\n└─ {}: 'Image'\n",
+                index(parent),
+                index(child)
+            );
+            assert!(puzzle.to_string().ends_with(&footer));
+        }
+    }
+}
@@ -7,7 +7,7 @@
 pub mod slot;
 
 use crate::error::*;
-use ndarray::{Array, Array2, ArrayView};
+use ndarray::{Array, Array2, ArrayView, Axis};
 use slot::SlotStatus;
 use std::fmt;
 
@@ -30,17 +30,35 @@ impl PieceArray {
         Self { array, offset_list }
     }
 
-    pub fn add_piece(&mut self, piece_index: usize, start: usize, end: usize) -> Result<()> {
+    /// Add a piece from `start` to `end`. A piece nested in a `container` is
+    /// drawn in the column of its container, if no other piece nested there
+    /// overlaps it, and if some of the container stays visible.
+    pub fn add_piece(
+        &mut self,
+        piece_index: usize,
+        start: usize,
+        end: usize,
+        container: Option<usize>,
+    ) -> Result<()> {
         let (start_index, end_index) = self.find_insert_index(start, end)?;
 
-        let col_index = match self.find_empty_column(start_index, end_index) {
-            Ok(col_index) => col_index,
-            // No empty place found, create a new column to hold our piece
-            Err(Error::FreeColNotFound) => {
-                self.push_col()?;
-                self.array.ncols() - 1
-            }
-            Err(e) => return Err(e),
+        let nested_col = container.and_then(|c| {
+            self.locate(c).map(|(col, _, _)| col).filter(|&col| {
+                self.holds(col, start_index, end_index, c)
+                    && self.keeps_cell(col, start_index, end_index, c)
+            })
+        });
+        let col_index = match nested_col {
+            Some(col_index) => col_index,
+            None => match self.find_empty_column(start_index, end_index) {
+                Ok(col_index) => col_index,
+                // No empty place found, create a new column to hold our piece
+                Err(Error::FreeColNotFound) => {
+                    self.push_col()?;
+                    self.array.ncols() - 1
+                }
+                Err(e) => return Err(e),
+            },
         };
 
         let mut new_col = self.array.column_mut(col_index);
@@ -49,7 +67,83 @@ impl PieceArray {
         }
 
         // TODO: replace assert by unit tests
-        Ok(assert_eq!(self.array.nrows() + 1, self.offset_list.len()))
+        assert_eq!(self.array.nrows() + 1, self.offset_list.len());
+        Ok(())
+    }
+
+    /// Draw a piece already added in the column of its new `container`, with
+    /// the pieces nested in it, unless it would hide the container. Its cells
+    /// go back to its `former` container, if it was drawn in its column.
+    /// Return whether the piece could be moved.
+    pub fn nest_piece(
+        &mut self,
+        piece_index: usize,
+        container: usize,
+        former: Option<usize>,
+    ) -> bool {
+        let (col, start_index, end_index) = match self.locate(piece_index) {
+            Some(location) => location,
+            None => return false,
+        };
+        let container_col = match self.locate(container) {
+            Some((container_col, _, _)) => container_col,
+            None => return false,
+        };
+        if col == container_col
+            || !self.holds(container_col, start_index, end_index, container)
+            || !self.keeps_cell(container_col, start_index, end_index, container)
+        {
+            return false;
+        }
+
+        let vacated = match former {
+            Some(f) if self.locate(f).map(|(c, _, _)| c) == Some(col) => SlotStatus::Used(f),
+            _ => SlotStatus::Free,
+        };
+        for i in start_index..end_index {
+            self.array[[i, container_col]] = self.array[[i, col]];
+            self.array[[i, col]] = vacated;
+        }
+        // the piece may have been alone in its column
+        if self.array.column(col).iter().all(|slot| slot.is_free()) {
+            let kept: Vec<usize> = (0..self.array.ncols()).filter(|&c| c != col).collect();
+            self.array = self.array.select(Axis(1), &kept);
+        }
+        true
+    }
+
+    // column holding the piece, with the first and the last (excluded) rows
+    // it spans
+    fn locate(&self, piece_index: usize) -> Option<(usize, usize, usize)> {
+        let used = SlotStatus::Used(piece_index);
+        self.array
+            .columns()
+            .into_iter()
+            .enumerate()
+            .find_map(|(n, column)| {
+                let first = column.iter().position(|&slot| slot == used)?;
+                let last = column.iter().rposition(|&slot| slot == used)?;
+                Some((n, first, last + 1))
+            })
+    }
+
+    // whether the rows of the column are all the cells of the piece
+    fn holds(&self, col: usize, start_index: usize, end_index: usize, piece_index: usize) -> bool {
+        (start_index..end_index).all(|i| self.array[[i, col]] == SlotStatus::Used(piece_index))
+    }
+
+    // whether the piece has a cell of the column outside of the rows, so that
+    // it stays visible once they are given to another piece
+    fn keeps_cell(
+        &self,
+        col: usize,
+        start_index: usize,
+        end_index: usize,
+        piece_index: usize,
+    ) -> bool {
+        self.array.column(col).iter().enumerate().any(|(i, &slot)| {
+            slot == SlotStatus::Used(piece_index) && !(start_index..end_index).contains(&i)
+        })
     }
 
     fn find_empty_column(&self, start_index: usize, end_index: usize) -> Result<usize> {
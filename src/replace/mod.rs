@@ -0,0 +1,121 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use crate::puzzle::PuzzlePiece;
+
+/// End of the space available to a binary replacing the one of `old_len`
+/// bytes at `offset`: the start of the next piece, or the end of the piece
+/// holding it (e.g. a partition), whichever comes first. The pieces nested in
+/// the old binary are replaced along with it. The piece setting the bound is
+/// returned along with it, if any.
+pub fn available(
+    pieces: &[PuzzlePiece],
+    offset: usize,
+    old_len: usize,
+    image_size: usize,
+) -> (usize, Option<&PuzzlePiece>) {
+    let old_end = offset + old_len;
+    let mut bound = (image_size, None);
+    for p in pieces.iter() {
+        let p_end = p.start() + p.len();
+        let end = if p.start() >= old_end {
+            p.start()
+        } else if p.start() >= offset && p_end <= old_end {
+            // nested in the old binary, or the old binary itself
+            continue;
+        } else if p.start() <= offset && offset < p_end {
+            // holding the old binary
+            p_end
+        } else if p.start() > offset {
+            // crossing the end of the old binary
+            old_end
+        } else {
+            continue;
+        };
+        if end < bound.0 {
+            bound = (end, Some(p));
+        }
+    }
+    bound
+}
+
+/// Write `new` at `offset` of the image, in place of a binary of `old_len`
+/// bytes: the rest of the old binary is filled with `fill`. The new binary
+/// must end before `limit`.
+pub fn replace(
+    image: &mut [u8],
+    offset: usize,
+    old_len: usize,
+    new: &[u8],
+    fill: u8,
+    limit: usize,
+) -> Result<()> {
+    if offset + new.len() > limit {
+        return Err(Error::DoesNotFit(format!("{:#x} bytes", new.len())));
+    }
+    image[offset..offset + new.len()].copy_from_slice(new);
+    if new.len() < old_len {
+        image[offset + new.len()..offset + old_len].fill(fill);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pieces() -> Vec<PuzzlePiece> {
+        vec![
+            PuzzlePiece::region("rootfs".to_string(), 0x4000, 0x1000),
+            PuzzlePiece::new("rootfs.img".to_string(), 0x2000, 0x1000),
+            // found in the old binary
+            PuzzlePiece::new("busybox".to_string(), 0x800, 0x1400),
+            PuzzlePiece::new("data.img".to_string(), 0x1000, 0x6000),
+        ]
+    }
+
+    #[test]
+    fn nested_pieces() {
+        let pieces = pieces();
+        let (limit, bound) = available(&pieces, 0x1000, 0x2000, 0x8000);
+        assert_eq!(limit, 0x5000);
+        assert_eq!(bound.map(|p| p.name()), Some("rootfs"));
+    }
+
+    #[test]
+    fn next_piece() {
+        let pieces = pieces();
+        let (limit, bound) = available(&pieces, 0x5000, 0x400, 0x8000);
+        assert_eq!(limit, 0x6000);
+        assert_eq!(bound.map(|p| p.name()), Some("data.img"));
+        let (limit, bound) = available(&pieces, 0x6000, 0x1000, 0x8000);
+        assert_eq!((limit, bound.is_none()), (0x8000, true));
+        // a piece crossing the end of the old binary
+        let (limit, bound) = available(&pieces, 0x1000, 0x600, 0x8000);
+        assert_eq!(limit, 0x1600);
+        assert_eq!(bound.map(|p| p.name()), Some("busybox"));
+    }
+
+    #[test]
+    fn fill_and_overflow() {
+        let mut image = vec![0xaa; 16];
+        replace(&mut image, 4, 6, &[1, 2], 0xff, 12).unwrap();
+        let mut expected = vec![0xaa; 16];
+        expected[4..6].copy_from_slice(&[1, 2]);
+        expected[6..10].fill(0xff);
+        assert_eq!(image, expected);
+
+        // a new binary running into the next piece
+        assert!(matches!(
+            replace(&mut image, 4, 6, &[0; 9], 0xff, 12),
+            Err(Error::DoesNotFit(_))
+        ));
+        assert_eq!(image, expected);
+        replace(&mut image, 4, 6, &[0; 8], 0xff, 12).unwrap();
+        assert_eq!(image[4..12], [0; 8]);
+    }
+}
@@ -6,15 +6,20 @@
 //
 use std::collections::hash_map::DefaultHasher;
 use std::{
-    fs::{metadata, File},
+    collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
-    io::{prelude::*, BufReader, SeekFrom},
     path::Path,
 };
 
 use crate::error::*;
+use crate::input::{self, Range};
+use sha2::{Digest, Sha256};
 
 const HEADER_SZ: usize = 16;
+// blocks found more often are patterns (e.g. JFFS2 clean markers), not copies
+const MAX_COPIES: usize = 16;
+// smallest mirrored copy reported
+const MIN_MIRROR_SZ: usize = 0x1000;
 
 #[derive(Debug)]
 pub struct ImgHashTable {
@@ -29,62 +34,38 @@ fn compute_hash<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
-fn compute_hash_by_block(f: &File, block_size: usize) -> Result<Vec<ImgHashTable>> {
-    let flash_size = f.metadata()?.len() as usize;
-    let mut reader = BufReader::new(f);
-
-    reader.seek(SeekFrom::Start(0))?;
-
-    let mut offset: usize = 0;
-    let remaining = flash_size % block_size;
-    let mut buffer = vec![0u8; block_size];
+fn compute_hash_by_block(data: &[u8], block_size: usize) -> Vec<ImgHashTable> {
     let mut table = Vec::<ImgHashTable>::new();
 
-    loop {
-        let size_r = reader.read(&mut buffer)?;
-
-        if size_r != block_size {
-            if size_r == remaining {
-                if remaining != 0 {
-                    buffer.truncate(remaining);
-                } else {
-                    // EOF reached and all file processed
-                    break;
-                }
-            } else {
-                // Unexpected EOF
-                return Err(Error::ReadErr);
-            }
-        }
-
-        let hash = compute_hash(&buffer);
+    for (n, block) in data.chunks(block_size).enumerate() {
+        let hash = compute_hash(&block);
+        // the last block may be shorter than the header itself:
+        // keep it zero-padded in that case
         let mut header = [0u8; HEADER_SZ];
-        header.copy_from_slice(&buffer[0..HEADER_SZ]);
+        let header_len = std::cmp::min(HEADER_SZ, block.len());
+        header[..header_len].copy_from_slice(&block[..header_len]);
 
         let hash_elem = ImgHashTable {
-            offset,
+            offset: n * block_size,
             hash,
             header,
         };
         table.push(hash_elem);
-
-        offset += block_size;
-
-        if size_r == remaining {
-            break;
-        }
     }
 
-    Ok(table)
+    table
 }
 
 fn locate_image_in_table(
-    flash_hash_table: &Vec<ImgHashTable>,
-    image_hash_table: &Vec<ImgHashTable>,
+    flash_hash_table: &[ImgHashTable],
+    image_hash_table: &[ImgHashTable],
 ) -> Vec<usize> {
     let mut found = Vec::<usize>::new();
 
     let image_len = image_hash_table.len();
+    if image_len == 0 || image_len > flash_hash_table.len() {
+        return found;
+    }
     let end = flash_hash_table.len() - image_len + 1;
 
     for (i, flash_elem) in flash_hash_table[..end].iter().enumerate() {
@@ -129,37 +110,334 @@ fn locate_image_in_table(
 }
 
 pub struct FlashImage {
+    data: Vec<u8>,
     table: Vec<ImgHashTable>,
-    size: u64,
 }
 
 impl FlashImage {
-    pub fn new<P: AsRef<Path> + Copy>(flash_img_path: P, block_size: usize) -> Result<Self> {
-        assert!(block_size > HEADER_SZ);
+    pub fn new<P: AsRef<Path>>(flash_img_path: P, block_size: usize) -> Result<Self> {
+        if block_size <= HEADER_SZ {
+            return Err(Error::BlockSize(HEADER_SZ));
+        }
 
-        let size = metadata(flash_img_path)?.len();
+        // compressed dumps are decompressed: offsets refer to the raw content
+        let data = input::read(flash_img_path)?;
 
-        let f = File::open(flash_img_path)?;
-        let table = compute_hash_by_block(&f, block_size)?;
+        Ok(Self::from_data(data, block_size))
+    }
 
-        Ok(FlashImage { table, size })
+    /// Flash image built in memory
+    pub fn from_data(data: Vec<u8>, block_size: usize) -> Self {
+        assert!(block_size > HEADER_SZ);
+
+        let table = compute_hash_by_block(&data, block_size);
+
+        FlashImage { data, table }
     }
 
     pub fn size(&self) -> u64 {
-        self.size
+        self.data.len() as u64
+    }
+
+    /// Raw content of the flash image
+    pub fn data(&self) -> &[u8] {
+        &self.data
     }
 
+    /// Search each range of the binary file `image_path`
     pub fn seek_image<P: AsRef<Path>>(
         &self,
         image_path: P,
         block_size: usize,
-    ) -> Result<Vec<usize>> {
-        let bin_file = File::open(image_path)?;
+    ) -> Result<Vec<(Range, Vec<usize>)>> {
+        let ranges = input::load(image_path)?;
 
-        let image_hash_table = compute_hash_by_block(&bin_file, block_size)?;
+        Ok(ranges
+            .into_iter()
+            .map(|range| {
+                let offsets = self.seek_data(range.data(), block_size);
+                (range, offsets)
+            })
+            .collect())
+    }
+
+    pub fn seek_data(&self, bin_data: &[u8], block_size: usize) -> Vec<usize> {
+        let image_hash_table = compute_hash_by_block(bin_data, block_size);
+
+        locate_image_in_table(&self.table, &image_hash_table)
+    }
+
+    /// Compare the flash image with `other`, hashed with the same block size,
+    /// see [`varying_blocks`]
+    pub fn diff(&self, other: &FlashImage, block_size: usize) -> Vec<(usize, usize)> {
+        varying_blocks(&[self, other], block_size)
+    }
 
-        let found = locate_image_in_table(&self.table, &image_hash_table);
+    /// Size of the prefix the flash image is made of repeated copies of, if
+    /// any: the mark of a dump of a chip read with the wrong size, or with
+    /// misconfigured address lines. Only the power-of-two sizes are tried,
+    /// whatever the number of copies (e.g. three copies of an 8 MiB chip), and
+    /// erased or zeroed images are not considered mirrored.
+    pub fn mirror_size(&self) -> Option<usize> {
+        let len = self.data.len();
+        if self.data.iter().all(|b| *b == self.data[0]) {
+            return None;
+        }
+        // the smallest prefix: the copies of a mirror are themselves mirrored
+        let mut size = MIN_MIRROR_SZ;
+        while size <= len / 2 {
+            if len.is_multiple_of(size) {
+                let prefix = &self.data[..size];
+                if self.data.chunks(size).all(|copy| copy == prefix) {
+                    return Some(size);
+                }
+            }
+            size *= 2;
+        }
+        None
+    }
+
+    // whether all the bytes of the n-th block are the same, as in erased or
+    // zeroed areas
+    fn is_uniform(&self, n: usize, block_size: usize) -> bool {
+        let block = &self.data[n * block_size..];
+        let block = &block[..std::cmp::min(block_size, block.len())];
+        block.iter().all(|b| *b == block[0])
+    }
+
+    /// Find the runs of at least `min_size` bytes of blocks appearing more
+    /// than once in the flash image (A/B slots, redundant copies...), as
+    /// (size, offsets) couples. The runs neither start nor end with an erased
+    /// or zeroed block, and the copies do not overlap.
+    pub fn duplicates(&self, block_size: usize, min_size: usize) -> Vec<(usize, Vec<usize>)> {
+        let same = |a: usize, b: usize| {
+            let (x, y) = (&self.table[a], &self.table[b]);
+            x.hash == y.hash && x.header == y.header
+        };
+        let mut by_hash: HashMap<(u64, [u8; HEADER_SZ]), Vec<usize>> = HashMap::new();
+        for (n, elem) in self.table.iter().enumerate() {
+            if !self.is_uniform(n, block_size) {
+                by_hash.entry((elem.hash, elem.header)).or_default().push(n);
+            }
+        }
+        let mut candidates: Vec<&Vec<usize>> = by_hash
+            .values()
+            .filter(|blocks| blocks.len() > 1 && blocks.len() <= MAX_COPIES)
+            .collect();
+        candidates.sort();
+
+        // runs of identical blocks between two copies, as (first block,
+        // second block, number of blocks)
+        let mut runs: Vec<(usize, usize, usize)> = Vec::new();
+        let mut visited: HashSet<(usize, usize)> = HashSet::new();
+        for blocks in candidates {
+            for (i, &a) in blocks.iter().enumerate() {
+                for &b in blocks[i + 1..].iter() {
+                    if visited.contains(&(a, b)) {
+                        continue;
+                    }
+                    let mut len = 0;
+                    while b + len < self.table.len() && a + len < b && same(a + len, b + len) {
+                        visited.insert((a + len, b + len));
+                        len += 1;
+                    }
+                    while len > 0 && self.is_uniform(a + len - 1, block_size) {
+                        len -= 1;
+                    }
+                    runs.push((a, b, len));
+                }
+            }
+        }
+
+        // the copies of a run starting at the same block form a group
+        let mut groups: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (a, b, len) in runs {
+            let size = std::cmp::min(len * block_size, self.data.len() - b * block_size);
+            if size >= min_size && size > 0 {
+                let copies = groups.entry((a, size)).or_insert_with(|| vec![a]);
+                copies.push(b);
+            }
+        }
+        let mut duplicates: Vec<(usize, Vec<usize>)> = groups
+            .into_iter()
+            .map(|((_, size), blocks)| (size, blocks.iter().map(|b| b * block_size).collect()))
+            .collect();
+        duplicates.sort_by_key(|(_, offsets)| offsets[0]);
+        // drop the groups included in a larger one, e.g. the second and third
+        // of three copies
+        let all = duplicates.clone();
+        duplicates.retain(|(size, offsets)| {
+            !all.iter().any(|(s, o)| {
+                s == size && o.len() > offsets.len() && offsets.iter().all(|x| o.contains(x))
+            })
+        });
+        duplicates
+    }
+
+    /// Find the contents of `size` bytes, starting at a block boundary, whose
+    /// SHA-256 digest is one of `digests`: return the index of the digest and
+    /// the offset of each of them
+    pub fn seek_sha256(&self, size: usize, digests: &[&[u8; 32]]) -> Vec<(usize, usize)> {
+        let mut found = Vec::new();
+        for elem in self.table.iter() {
+            // the size comes from the manifest
+            let end = match elem.offset.checked_add(size) {
+                Some(end) => end,
+                None => continue,
+            };
+            let candidate = match self.data.get(elem.offset..end) {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            let digest = Sha256::digest(candidate);
+            for (i, _) in digests
+                .iter()
+                .enumerate()
+                .filter(|(_, d)| d[..] == digest[..])
+            {
+                found.push((i, elem.offset));
+            }
+        }
+        found
+    }
+}
+
+/// Compare flash images hashed with the same block size, block by block:
+/// return the ranges of consecutive blocks differing in at least one of them,
+/// as (offset, size) couples. The blocks past the end of the shorter images
+/// differ.
+pub fn varying_blocks(images: &[&FlashImage], block_size: usize) -> Vec<(usize, usize)> {
+    let end = images.iter().map(|i| i.data.len()).max().unwrap_or(0);
+    let nb_blocks = images.iter().map(|i| i.table.len()).max().unwrap_or(0);
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for n in 0..nb_blocks {
+        let reference = images[0].table.get(n);
+        let same = images.iter().all(|i| match (i.table.get(n), reference) {
+            (Some(a), Some(b)) => a.hash == b.hash && a.header == b.header,
+            _ => false,
+        });
+        if same {
+            continue;
+        }
+        let offset = n * block_size;
+        let size = std::cmp::min(block_size, end - offset);
+        match ranges.last_mut() {
+            Some((start, len)) if *start + *len == offset => *len += size,
+            _ => ranges.push((offset, size)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BSIZE: usize = 512;
+
+    // `n` blocks unlike any other of the image
+    fn blocks(seed: u32, n: usize) -> Vec<u8> {
+        let mut x = seed;
+        (0..n * BSIZE)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    // erased image of `n` blocks holding each content at its block
+    fn flash_image(n: usize, contents: &[(usize, &[u8])]) -> FlashImage {
+        let mut data = vec![0xff; n * BSIZE];
+        for (block, content) in contents.iter() {
+            data[block * BSIZE..block * BSIZE + content.len()].copy_from_slice(content);
+        }
+        FlashImage::from_data(data, BSIZE)
+    }
+
+    #[test]
+    fn sha256() {
+        let mut data = vec![0xff; 4 * BSIZE];
+        data[BSIZE..BSIZE + 5].copy_from_slice(b"hello");
+        let image = FlashImage::from_data(data, BSIZE);
+        let hello: [u8; 32] = Sha256::digest(b"hello").into();
+        let erased: [u8; 32] = Sha256::digest([0xff; 8]).into();
+        assert_eq!(image.seek_sha256(5, &[&hello]), [(0, BSIZE)]);
+        // found in several blocks
+        assert_eq!(
+            image.seek_sha256(8, &[&hello, &erased]),
+            [(1, 0), (1, 2 * BSIZE), (1, 3 * BSIZE)]
+        );
+        // artefacts larger than the image
+        assert!(image.seek_sha256(8 * BSIZE, &[&hello]).is_empty());
+        assert!(image.seek_sha256(usize::MAX, &[&hello]).is_empty());
+    }
+
+    #[test]
+    fn duplicates() {
+        let a = blocks(1, 4);
+        let image = flash_image(16, &[(1, &a), (6, &blocks(2, 1)), (8, &a)]);
+        assert_eq!(
+            image.duplicates(BSIZE, 0),
+            [(4 * BSIZE, vec![BSIZE, 8 * BSIZE])]
+        );
+        // the runs shorter than the minimum size
+        assert_eq!(image.duplicates(BSIZE, 4 * BSIZE).len(), 1);
+        assert!(image.duplicates(BSIZE, 5 * BSIZE).is_empty());
+
+        // three copies
+        let image = flash_image(16, &[(0, &a), (5, &a), (10, &a)]);
+        assert_eq!(
+            image.duplicates(BSIZE, 0),
+            [(4 * BSIZE, vec![0, 5 * BSIZE, 10 * BSIZE])]
+        );
+    }
+
+    #[test]
+    fn uniform_blocks() {
+        // copies starting and ending with zeroed and erased blocks
+        let mut a = vec![0; BSIZE];
+        a.extend(blocks(1, 2));
+        a.extend([0; BSIZE]);
+        a.extend([0xff; BSIZE]);
+        let image = flash_image(16, &[(0, &a), (8, &a)]);
+        assert_eq!(
+            image.duplicates(BSIZE, 0),
+            [(2 * BSIZE, vec![BSIZE, 9 * BSIZE])]
+        );
+
+        // erased and zeroed images hold no duplicates
+        let image = flash_image(16, &[(8, &[0; 8 * BSIZE])]);
+        assert!(image.duplicates(BSIZE, 0).is_empty());
+    }
+
+    #[test]
+    fn overlapping_copies() {
+        // a repeated pattern of two blocks: the copies do not overlap
+        let ab = blocks(1, 2);
+        let image = flash_image(6, &[(0, &ab), (2, &ab), (4, &ab)]);
+        assert_eq!(
+            image.duplicates(BSIZE, 0),
+            [(2 * BSIZE, vec![0, 2 * BSIZE, 4 * BSIZE])]
+        );
+    }
+
+    #[test]
+    fn mirrors() {
+        let chip = blocks(1, 2 * MIN_MIRROR_SZ / BSIZE);
+        for copies in [2, 3, 4] {
+            let image = FlashImage::from_data(chip.repeat(copies), BSIZE);
+            assert_eq!(image.mirror_size(), Some(chip.len()), "{} copies", copies);
+        }
+        // a chip itself made of copies
+        let image = FlashImage::from_data(chip[..MIN_MIRROR_SZ].repeat(6), BSIZE);
+        assert_eq!(image.mirror_size(), Some(MIN_MIRROR_SZ));
 
-        Ok(found)
+        let mut data = chip.repeat(4);
+        data[3 * chip.len()] ^= 1;
+        assert_eq!(FlashImage::from_data(data, BSIZE).mirror_size(), None);
+        assert_eq!(FlashImage::from_data(chip, BSIZE).mirror_size(), None);
+        let erased = FlashImage::from_data(vec![0xff; 4 * MIN_MIRROR_SZ], BSIZE);
+        assert_eq!(erased.mirror_size(), None);
     }
 }
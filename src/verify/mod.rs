@@ -0,0 +1,297 @@
+//
+// This file is part of imgseek
+//
+// Copyright (C) 2022 Paul-Erwan RIO <paulerwan.rio@proton.me>
+//
+//
+use crate::error::*;
+use crate::input;
+use crate::seeker::FlashImage;
+use colored::Colorize;
+use serde::Deserialize;
+use std::{collections::HashSet, fmt, fs, path::Path};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default, rename = "binary")]
+    binaries: Vec<Entry>,
+}
+
+/// A binary expected at a given offset of the flash image, as listed in the
+/// manifest:
+/// ```toml
+/// [[binary]]
+/// offset = 0x40000
+/// file = "u-boot.bin"
+/// alternatives = ["u-boot-rev2.bin"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    offset: usize,
+    file: String,
+    #[serde(default)]
+    alternatives: Vec<String>,
+}
+
+/// Parse a verification manifest
+pub fn parse(content: &str) -> Result<Vec<Entry>> {
+    let manifest: Manifest = toml::from_str(content)?;
+    Ok(manifest.binaries)
+}
+
+// a binary allowed at the offset of an entry, as its ranges (shift relative
+// to the first one, data)
+struct Candidate {
+    name: String,
+    ranges: Vec<(usize, Vec<u8>)>,
+}
+
+impl Candidate {
+    fn load(base_dir: &Path, name: &str) -> Result<Self> {
+        let ranges = input::load(base_dir.join(name))?;
+        let base = ranges.first().and_then(|r| r.address()).unwrap_or(0);
+        let ranges = ranges
+            .into_iter()
+            .map(|r| {
+                (
+                    r.address().map_or(0, |a| (a - base) as usize),
+                    r.into_data(),
+                )
+            })
+            .collect();
+        Ok(Candidate {
+            name: name.to_string(),
+            ranges,
+        })
+    }
+
+    // offsets of the flash image holding all the ranges of the binary
+    fn seek(&self, flash_image: &FlashImage, bsize: usize) -> Vec<usize> {
+        let mut found: Option<Vec<usize>> = None;
+        for (shift, data) in self.ranges.iter() {
+            let offsets: HashSet<usize> = flash_image
+                .seek_data(data, bsize)
+                .into_iter()
+                .filter_map(|o| o.checked_sub(*shift))
+                .collect();
+            found = Some(match found {
+                None => offsets.into_iter().collect(),
+                Some(found) => found.into_iter().filter(|o| offsets.contains(o)).collect(),
+            });
+        }
+        let mut found = found.unwrap_or_default();
+        found.sort_unstable();
+        found
+    }
+}
+
+/// Result of the verification of an entry of the manifest, or of a binary
+/// found where it is not expected
+#[derive(Debug)]
+pub enum Verdict {
+    /// the binary is at its offset, or the alternative named
+    Pass(Option<String>),
+    /// none of the binaries allowed are at the offset, maybe elsewhere
+    Misplaced(Vec<(String, usize)>),
+    /// a binary of the manifest found at an offset it is not expected at
+    Extra(String),
+}
+
+impl Verdict {
+    pub fn is_pass(&self) -> bool {
+        matches!(self, Self::Pass(_))
+    }
+}
+
+/// Number of verdicts failing the verification
+pub fn failures(verdicts: &[(usize, Option<String>, Verdict)]) -> usize {
+    verdicts.iter().filter(|(_, _, v)| !v.is_pass()).count()
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Pass(None) => write!(f, "{}", "PASS     ".green()),
+            Verdict::Pass(Some(name)) => write!(f, "{}  '{}'", "PASS     ".green(), name),
+            Verdict::Misplaced(found) if found.is_empty() => {
+                write!(f, "{}", "MISSING  ".red())
+            }
+            Verdict::Misplaced(found) => {
+                write!(f, "{}  found", "MISPLACED".red())?;
+                for (name, offset) in found.iter() {
+                    write!(f, " '{}' at {:#010x}", name, offset)?;
+                }
+                Ok(())
+            }
+            Verdict::Extra(name) => write!(f, "{}  '{}'", "EXTRA    ".red(), name),
+        }
+    }
+}
+
+/// Check the flash image against the manifest `manifest_path`, whose binaries
+/// are relative to the manifest. Each entry gets a verdict, and each binary of
+/// the manifest found at an offset no entry expects it at gets an `Extra` one,
+/// as (offset, expected file, verdict) tuples sorted by offset.
+pub fn verify<P: AsRef<Path>>(
+    manifest_path: P,
+    flash_image: &FlashImage,
+    bsize: usize,
+) -> Result<Vec<(usize, Option<String>, Verdict)>> {
+    let entries = parse(&fs::read_to_string(&manifest_path)?)?;
+    let base_dir = manifest_path
+        .as_ref()
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+
+    // the binaries may be listed at several offsets, e.g. redundant copies
+    let expected: HashSet<(&str, usize)> = entries
+        .iter()
+        .flat_map(|e| {
+            std::iter::once(&e.file)
+                .chain(e.alternatives.iter())
+                .map(move |name| (name.as_str(), e.offset))
+        })
+        .collect();
+
+    let mut verdicts = Vec::new();
+    let mut extras: HashSet<(usize, String)> = HashSet::new();
+    for entry in entries.iter() {
+        let mut found = Vec::new();
+        for name in std::iter::once(&entry.file).chain(entry.alternatives.iter()) {
+            let candidate = Candidate::load(base_dir, name)?;
+            for offset in candidate.seek(flash_image, bsize) {
+                found.push((candidate.name.clone(), offset));
+            }
+        }
+
+        let verdict = match found.iter().find(|(_, o)| *o == entry.offset) {
+            Some((name, _)) => {
+                for (name, offset) in found.iter() {
+                    if !expected.contains(&(name.as_str(), *offset)) {
+                        extras.insert((*offset, name.clone()));
+                    }
+                }
+                Verdict::Pass(Some(name).filter(|n| **n != entry.file).cloned())
+            }
+            None => Verdict::Misplaced(found),
+        };
+        verdicts.push((entry.offset, Some(entry.file.clone()), verdict));
+    }
+    for (offset, name) in extras {
+        verdicts.push((offset, None, Verdict::Extra(name)));
+    }
+    verdicts.sort_by_key(|(offset, _, _)| *offset);
+    Ok(verdicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BSIZE: usize = 512;
+
+    // content unlike any other block of the image
+    fn binary(seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..0x400)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verdicts() {
+        let dir = std::env::temp_dir().join(format!("imgseek-verify-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, seed) in [
+            ("a.bin", 1),
+            ("b.bin", 2),
+            ("b2.bin", 3),
+            ("c.bin", 4),
+            ("d.bin", 5),
+        ] {
+            fs::write(dir.join(name), binary(seed)).unwrap();
+        }
+        let manifest = dir.join("manifest.toml");
+        fs::write(
+            &manifest,
+            r#"
+[[binary]]
+offset = 0
+file = "a.bin"
+
+[[binary]]
+offset = 0x400
+file = "b.bin"
+alternatives = ["b2.bin"]
+
+[[binary]]
+offset = 0x800
+file = "c.bin"
+
+[[binary]]
+offset = 0x1000
+file = "d.bin"
+"#,
+        )
+        .unwrap();
+
+        let mut data = vec![0xff; 0x2000];
+        data[..0x400].copy_from_slice(&binary(1));
+        data[0x400..0x800].copy_from_slice(&binary(3));
+        data[0xc00..0x1000].copy_from_slice(&binary(4));
+        data[0x1400..0x1800].copy_from_slice(&binary(1));
+        let flash_image = FlashImage::from_data(data, BSIZE);
+
+        let verdicts = verify(&manifest, &flash_image, BSIZE).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let results: Vec<_> = verdicts
+            .iter()
+            .map(|(offset, name, v)| (*offset, name.as_deref(), v.is_pass()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (0, Some("a.bin"), true),
+                (0x400, Some("b.bin"), true),
+                (0x800, Some("c.bin"), false),
+                (0x1000, Some("d.bin"), false),
+                (0x1400, None, false),
+            ]
+        );
+        assert!(matches!(&verdicts[0].2, Verdict::Pass(None)));
+        assert!(matches!(&verdicts[1].2, Verdict::Pass(Some(n)) if n == "b2.bin"));
+        assert!(
+            matches!(&verdicts[2].2, Verdict::Misplaced(found) if found == &[("c.bin".to_string(), 0xc00)])
+        );
+        assert!(matches!(&verdicts[3].2, Verdict::Misplaced(found) if found.is_empty()));
+        assert!(matches!(&verdicts[4].2, Verdict::Extra(n) if n == "a.bin"));
+        assert_eq!(failures(&verdicts), 3);
+        assert_eq!(failures(&verdicts[..2]), 0);
+    }
+
+    #[test]
+    fn verdict_columns() {
+        let verdicts = [
+            Verdict::Pass(None),
+            Verdict::Pass(Some("b2.bin".to_string())),
+            Verdict::Misplaced(vec![("c.bin".to_string(), 0xc00)]),
+            Verdict::Misplaced(Vec::new()),
+            Verdict::Extra("a.bin".to_string()),
+        ];
+        let lines: Vec<String> = verdicts.iter().map(|v| v.to_string()).collect();
+        let expected = [
+            format!("{}", "PASS     ".green()),
+            format!("{}  'b2.bin'", "PASS     ".green()),
+            format!("{}  found 'c.bin' at 0x00000c00", "MISPLACED".red()),
+            format!("{}", "MISSING  ".red()),
+            format!("{}  'a.bin'", "EXTRA    ".red()),
+        ];
+        assert_eq!(lines, expected);
+    }
+}